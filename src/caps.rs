@@ -0,0 +1,440 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::str::{FromStr};
+
+const _LINUX_CAPABILITY_VERSION_3: u32 = 0x2008_0522;
+
+#[repr(C)]
+struct CapUserHeader {
+  version:  u32,
+  pid:      libc::c_int,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default)]
+struct CapUserData {
+  effective:    u32,
+  permitted:    u32,
+  inheritable:  u32,
+}
+
+/// A Linux capability, as defined in `<linux/capability.h>`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[repr(u8)]
+#[allow(non_camel_case_types)]
+pub enum Capability {
+  CAP_CHOWN = 0,
+  CAP_DAC_OVERRIDE = 1,
+  CAP_DAC_READ_SEARCH = 2,
+  CAP_FOWNER = 3,
+  CAP_FSETID = 4,
+  CAP_KILL = 5,
+  CAP_SETGID = 6,
+  CAP_SETUID = 7,
+  CAP_SETPCAP = 8,
+  CAP_LINUX_IMMUTABLE = 9,
+  CAP_NET_BIND_SERVICE = 10,
+  CAP_NET_BROADCAST = 11,
+  CAP_NET_ADMIN = 12,
+  CAP_NET_RAW = 13,
+  CAP_IPC_LOCK = 14,
+  CAP_IPC_OWNER = 15,
+  CAP_SYS_MODULE = 16,
+  CAP_SYS_RAWIO = 17,
+  CAP_SYS_CHROOT = 18,
+  CAP_SYS_PTRACE = 19,
+  CAP_SYS_PACCT = 20,
+  CAP_SYS_ADMIN = 21,
+  CAP_SYS_BOOT = 22,
+  CAP_SYS_NICE = 23,
+  CAP_SYS_RESOURCE = 24,
+  CAP_SYS_TIME = 25,
+  CAP_SYS_TTY_CONFIG = 26,
+  CAP_MKNOD = 27,
+  CAP_LEASE = 28,
+  CAP_AUDIT_WRITE = 29,
+  CAP_AUDIT_CONTROL = 30,
+  CAP_SETFCAP = 31,
+  CAP_MAC_OVERRIDE = 32,
+  CAP_MAC_ADMIN = 33,
+  CAP_SYSLOG = 34,
+  CAP_WAKE_ALARM = 35,
+  CAP_BLOCK_SUSPEND = 36,
+  CAP_AUDIT_READ = 37,
+  CAP_PERFMON = 38,
+  CAP_BPF = 39,
+  CAP_CHECKPOINT_RESTORE = 40,
+}
+
+static ALL_CAPABILITIES: &[Capability] = &[
+  Capability::CAP_CHOWN,
+  Capability::CAP_DAC_OVERRIDE,
+  Capability::CAP_DAC_READ_SEARCH,
+  Capability::CAP_FOWNER,
+  Capability::CAP_FSETID,
+  Capability::CAP_KILL,
+  Capability::CAP_SETGID,
+  Capability::CAP_SETUID,
+  Capability::CAP_SETPCAP,
+  Capability::CAP_LINUX_IMMUTABLE,
+  Capability::CAP_NET_BIND_SERVICE,
+  Capability::CAP_NET_BROADCAST,
+  Capability::CAP_NET_ADMIN,
+  Capability::CAP_NET_RAW,
+  Capability::CAP_IPC_LOCK,
+  Capability::CAP_IPC_OWNER,
+  Capability::CAP_SYS_MODULE,
+  Capability::CAP_SYS_RAWIO,
+  Capability::CAP_SYS_CHROOT,
+  Capability::CAP_SYS_PTRACE,
+  Capability::CAP_SYS_PACCT,
+  Capability::CAP_SYS_ADMIN,
+  Capability::CAP_SYS_BOOT,
+  Capability::CAP_SYS_NICE,
+  Capability::CAP_SYS_RESOURCE,
+  Capability::CAP_SYS_TIME,
+  Capability::CAP_SYS_TTY_CONFIG,
+  Capability::CAP_MKNOD,
+  Capability::CAP_LEASE,
+  Capability::CAP_AUDIT_WRITE,
+  Capability::CAP_AUDIT_CONTROL,
+  Capability::CAP_SETFCAP,
+  Capability::CAP_MAC_OVERRIDE,
+  Capability::CAP_MAC_ADMIN,
+  Capability::CAP_SYSLOG,
+  Capability::CAP_WAKE_ALARM,
+  Capability::CAP_BLOCK_SUSPEND,
+  Capability::CAP_AUDIT_READ,
+  Capability::CAP_PERFMON,
+  Capability::CAP_BPF,
+  Capability::CAP_CHECKPOINT_RESTORE,
+];
+
+impl Capability {
+  /// All capabilities known to this crate, in numeric order.
+  pub fn all() -> &'static [Capability] {
+    ALL_CAPABILITIES
+  }
+
+  pub fn from_index(index: u32) -> Option<Capability> {
+    ALL_CAPABILITIES.get(index as usize).copied()
+  }
+
+  pub fn index(self) -> u32 {
+    self as u32
+  }
+
+  pub fn name(self) -> &'static str {
+    match self {
+      Capability::CAP_CHOWN => "CAP_CHOWN",
+      Capability::CAP_DAC_OVERRIDE => "CAP_DAC_OVERRIDE",
+      Capability::CAP_DAC_READ_SEARCH => "CAP_DAC_READ_SEARCH",
+      Capability::CAP_FOWNER => "CAP_FOWNER",
+      Capability::CAP_FSETID => "CAP_FSETID",
+      Capability::CAP_KILL => "CAP_KILL",
+      Capability::CAP_SETGID => "CAP_SETGID",
+      Capability::CAP_SETUID => "CAP_SETUID",
+      Capability::CAP_SETPCAP => "CAP_SETPCAP",
+      Capability::CAP_LINUX_IMMUTABLE => "CAP_LINUX_IMMUTABLE",
+      Capability::CAP_NET_BIND_SERVICE => "CAP_NET_BIND_SERVICE",
+      Capability::CAP_NET_BROADCAST => "CAP_NET_BROADCAST",
+      Capability::CAP_NET_ADMIN => "CAP_NET_ADMIN",
+      Capability::CAP_NET_RAW => "CAP_NET_RAW",
+      Capability::CAP_IPC_LOCK => "CAP_IPC_LOCK",
+      Capability::CAP_IPC_OWNER => "CAP_IPC_OWNER",
+      Capability::CAP_SYS_MODULE => "CAP_SYS_MODULE",
+      Capability::CAP_SYS_RAWIO => "CAP_SYS_RAWIO",
+      Capability::CAP_SYS_CHROOT => "CAP_SYS_CHROOT",
+      Capability::CAP_SYS_PTRACE => "CAP_SYS_PTRACE",
+      Capability::CAP_SYS_PACCT => "CAP_SYS_PACCT",
+      Capability::CAP_SYS_ADMIN => "CAP_SYS_ADMIN",
+      Capability::CAP_SYS_BOOT => "CAP_SYS_BOOT",
+      Capability::CAP_SYS_NICE => "CAP_SYS_NICE",
+      Capability::CAP_SYS_RESOURCE => "CAP_SYS_RESOURCE",
+      Capability::CAP_SYS_TIME => "CAP_SYS_TIME",
+      Capability::CAP_SYS_TTY_CONFIG => "CAP_SYS_TTY_CONFIG",
+      Capability::CAP_MKNOD => "CAP_MKNOD",
+      Capability::CAP_LEASE => "CAP_LEASE",
+      Capability::CAP_AUDIT_WRITE => "CAP_AUDIT_WRITE",
+      Capability::CAP_AUDIT_CONTROL => "CAP_AUDIT_CONTROL",
+      Capability::CAP_SETFCAP => "CAP_SETFCAP",
+      Capability::CAP_MAC_OVERRIDE => "CAP_MAC_OVERRIDE",
+      Capability::CAP_MAC_ADMIN => "CAP_MAC_ADMIN",
+      Capability::CAP_SYSLOG => "CAP_SYSLOG",
+      Capability::CAP_WAKE_ALARM => "CAP_WAKE_ALARM",
+      Capability::CAP_BLOCK_SUSPEND => "CAP_BLOCK_SUSPEND",
+      Capability::CAP_AUDIT_READ => "CAP_AUDIT_READ",
+      Capability::CAP_PERFMON => "CAP_PERFMON",
+      Capability::CAP_BPF => "CAP_BPF",
+      Capability::CAP_CHECKPOINT_RESTORE => "CAP_CHECKPOINT_RESTORE",
+    }
+  }
+}
+
+impl fmt::Display for Capability {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.write_str(self.name())
+  }
+}
+
+impl FromStr for Capability {
+  type Err = Error;
+
+  /// Parses a capability name, with or without the `CAP_` prefix, ignoring
+  /// case.
+  fn from_str(s: &str) -> Result<Capability, Error> {
+    let upper = s.to_ascii_uppercase();
+    let name = if upper.starts_with("CAP_") { upper } else { format!("CAP_{}", upper) };
+    for &cap in ALL_CAPABILITIES.iter() {
+      if cap.name() == name {
+        return Ok(cap);
+      }
+    }
+    Err(Error::new(ErrorKind::InvalidInput, format!("unknown capability: {:?}", s)))
+  }
+}
+
+/// A set of capabilities, stored as a 64-bit mask indexed by capability
+/// number.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Hash)]
+#[repr(transparent)]
+pub struct Capabilities {
+  pub bits: u64,
+}
+
+impl Capabilities {
+  #[inline]
+  pub fn empty() -> Capabilities {
+    Capabilities{bits: 0}
+  }
+
+  /// The set of all capabilities known to this crate.
+  pub fn all() -> Capabilities {
+    ALL_CAPABILITIES.iter().copied().collect()
+  }
+
+  #[inline]
+  pub fn from_bits(bits: u64) -> Capabilities {
+    Capabilities{bits}
+  }
+
+  #[inline]
+  pub fn bits(&self) -> u64 {
+    self.bits
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.bits == 0
+  }
+
+  #[inline]
+  pub fn contains(&self, cap: Capability) -> bool {
+    (self.bits & (1 << cap.index())) != 0
+  }
+
+  #[inline]
+  pub fn insert(&mut self, cap: Capability) {
+    self.bits |= 1 << cap.index();
+  }
+
+  #[inline]
+  pub fn remove(&mut self, cap: Capability) {
+    self.bits &= !(1 << cap.index());
+  }
+
+  pub fn iter(&self) -> impl Iterator<Item=Capability> {
+    let bits = self.bits;
+    ALL_CAPABILITIES.iter().copied().filter(move |cap| (bits & (1 << cap.index())) != 0)
+  }
+}
+
+impl std::iter::FromIterator<Capability> for Capabilities {
+  fn from_iter<I: IntoIterator<Item=Capability>>(iter: I) -> Capabilities {
+    let mut caps = Capabilities::empty();
+    for cap in iter {
+      caps.insert(cap);
+    }
+    caps
+  }
+}
+
+impl fmt::Debug for Capabilities {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_set().entries(self.iter()).finish()
+  }
+}
+
+/// The effective, permitted, and inheritable capability sets of a thread.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct CapSet {
+  pub effective:    Capabilities,
+  pub permitted:    Capabilities,
+  pub inheritable:  Capabilities,
+}
+
+impl CapSet {
+  /// Returns the capability sets of the calling thread.
+  pub fn current() -> Result<CapSet, Error> {
+    CapSet::for_pid(0)
+  }
+
+  /// Returns the capability sets of the thread `pid`, or of the calling
+  /// thread if `pid` is 0.
+  ///
+  /// ## Notes
+  ///
+  /// * `capget()` is the underlying syscall.
+  pub fn for_pid(pid: libc::pid_t) -> Result<CapSet, Error> {
+    let mut hdr = CapUserHeader{version: _LINUX_CAPABILITY_VERSION_3, pid};
+    let mut data = [CapUserData::default(); 2];
+    let res = unsafe { libc::syscall(libc::SYS_capget, &mut hdr, data.as_mut_ptr()) };
+    if res != 0 {
+      return Err(Error::last_os_error());
+    }
+    let join = |lo: u32, hi: u32| Capabilities::from_bits((lo as u64) | ((hi as u64) << 32));
+    Ok(CapSet{
+      effective:    join(data[0].effective, data[1].effective),
+      permitted:    join(data[0].permitted, data[1].permitted),
+      inheritable:  join(data[0].inheritable, data[1].inheritable),
+    })
+  }
+
+  /// Sets the capability sets of the calling thread.
+  ///
+  /// ## Notes
+  ///
+  /// * `capset()` is the underlying syscall.
+  pub fn apply(&self) -> Result<(), Error> {
+    let mut hdr = CapUserHeader{version: _LINUX_CAPABILITY_VERSION_3, pid: 0};
+    let data = [
+      CapUserData{
+        effective:    self.effective.bits as u32,
+        permitted:    self.permitted.bits as u32,
+        inheritable:  self.inheritable.bits as u32,
+      },
+      CapUserData{
+        effective:    (self.effective.bits >> 32) as u32,
+        permitted:    (self.permitted.bits >> 32) as u32,
+        inheritable:  (self.inheritable.bits >> 32) as u32,
+      },
+    ];
+    let res = unsafe { libc::syscall(libc::SYS_capset, &mut hdr, data.as_ptr()) };
+    if res != 0 {
+      return Err(Error::last_os_error());
+    }
+    Ok(())
+  }
+}
+
+fn prctl(option: libc::c_int, arg2: libc::c_ulong, arg3: libc::c_ulong) -> Result<libc::c_int, Error> {
+  let res = unsafe { libc::prctl(option, arg2, arg3, 0 as libc::c_ulong, 0 as libc::c_ulong) };
+  if res < 0 {
+    return Err(Error::last_os_error());
+  }
+  Ok(res)
+}
+
+/// Returns whether `cap` is in the ambient set of the calling thread.
+pub fn ambient_is_set(cap: Capability) -> Result<bool, Error> {
+  let res = prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_IS_SET as _, cap.index() as _)?;
+  Ok(res != 0)
+}
+
+/// Adds `cap` to the ambient set of the calling thread.
+///
+/// The capability must already be in both the permitted and the inheritable
+/// sets.
+pub fn ambient_raise(cap: Capability) -> Result<(), Error> {
+  prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_RAISE as _, cap.index() as _)?;
+  Ok(())
+}
+
+/// Removes `cap` from the ambient set of the calling thread.
+pub fn ambient_lower(cap: Capability) -> Result<(), Error> {
+  prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_LOWER as _, cap.index() as _)?;
+  Ok(())
+}
+
+/// Clears the ambient set of the calling thread.
+pub fn ambient_clear() -> Result<(), Error> {
+  prctl(libc::PR_CAP_AMBIENT, libc::PR_CAP_AMBIENT_CLEAR_ALL as _, 0)?;
+  Ok(())
+}
+
+/// Returns whether `cap` is in the bounding set of the calling thread.
+pub fn bounding_is_set(cap: Capability) -> Result<bool, Error> {
+  let res = prctl(libc::PR_CAPBSET_READ, cap.index() as _, 0)?;
+  Ok(res != 0)
+}
+
+/// Drops `cap` from the bounding set of the calling thread.
+///
+/// This requires `CAP_SETPCAP` and cannot be undone.
+pub fn bounding_drop(cap: Capability) -> Result<(), Error> {
+  prctl(libc::PR_CAPBSET_DROP, cap.index() as _, 0)?;
+  Ok(())
+}
+
+/// Returns the highest capability number supported by the running kernel,
+/// from `/proc/sys/kernel/cap_last_cap`.
+pub fn last_cap() -> Result<u32, Error> {
+  let s = std::fs::read_to_string("/proc/sys/kernel/cap_last_cap")?;
+  s.trim().parse().map_err(|_| Error::new(ErrorKind::InvalidData, format!("bad cap_last_cap: {:?}", s)))
+}
+
+/// Drops every capability not in `keep` from the bounding set, including
+/// those newer than this crate knows about.
+///
+/// ## Notes
+///
+/// * The capabilities are numbered up to `last_cap()`; if that cannot be
+///   read, this drops capabilities until `PR_CAPBSET_DROP` fails with
+///   `EINVAL`.
+pub fn bounding_drop_all_except(keep: Capabilities) -> Result<(), Error> {
+  let last = last_cap().unwrap_or(u32::MAX);
+  for index in 0 ..= last {
+    if index < 64 && (keep.bits & (1 << index)) != 0 {
+      continue;
+    }
+    match prctl(libc::PR_CAPBSET_DROP, index as _, 0) {
+      Err(e) if e.raw_os_error() == Some(libc::EINVAL) => break,
+      res => res?,
+    };
+  }
+  Ok(())
+}
+
+/// Returns the "keep capabilities" flag of the calling thread.
+pub fn get_keep_caps() -> Result<bool, Error> {
+  let res = prctl(libc::PR_GET_KEEPCAPS, 0, 0)?;
+  Ok(res != 0)
+}
+
+/// Sets the "keep capabilities" flag of the calling thread.
+///
+/// While set, the permitted set is retained when all of the UIDs change
+/// from 0 to nonzero (e.g. in `set_uid`). The flag is reset on `execve`.
+pub fn set_keep_caps(keep: bool) -> Result<(), Error> {
+  prctl(libc::PR_SET_KEEPCAPS, keep as _, 0)?;
+  Ok(())
+}
+
+/// Switches from root to `uid` while retaining the capabilities in `keep`,
+/// which are left in both the permitted and the effective sets; all other
+/// capabilities are dropped.
+///
+/// The "keep capabilities" flag is set around the call to `set_uid` and
+/// cleared again afterwards.
+pub fn set_uid_keep_caps(uid: u32, keep: Capabilities) -> Result<(), Error> {
+  set_keep_caps(true)?;
+  let res = crate::set_uid(uid);
+  let reset = set_keep_caps(false);
+  res?;
+  reset?;
+  CapSet{
+    effective:    keep,
+    permitted:    keep,
+    inheritable:  Capabilities::empty(),
+  }.apply()
+}
@@ -6,10 +6,21 @@ This Source Code Form is subject to the terms of the Mozilla Public License, v.
 If a copy of the MPL was not distributed with this file,
 You can obtain one at http://mozilla.org/MPL/2.0/. */
 
+use std::fmt;
 use std::io::{self, Error};
 use std::mem::{zeroed};
-use std::ops::{BitAnd, BitOr};
+use std::ops::{BitAnd, BitAndAssign, BitOr, BitOrAssign, Not, Sub};
 use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr::{null};
+use std::sync::atomic::{AtomicU8, Ordering};
+use std::time::{Duration};
+
+use crate::{timeout_millis, to_timespec};
+use crate::signal::{SigSet};
+
+pub use self::registry::{Ready, Registry, Token};
+
+mod registry;
 
 #[repr(i32)]
 #[allow(non_camel_case_types)]
@@ -22,7 +33,7 @@ pub enum Control {
     EPOLL_CTL_DEL = libc::EPOLL_CTL_DEL,
 }
 
-#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash)]
 #[repr(transparent)]
 pub struct Events {
     pub bits: u32,
@@ -34,6 +45,16 @@ impl Events {
         Events{bits: 0}
     }
 
+    /// Returns the union of all the flags defined in this module.
+    #[inline]
+    pub fn all() -> Events {
+        let mut bits = 0;
+        for &(ev, _) in NAMED_EVENTS.iter() {
+            bits |= ev.bits;
+        }
+        Events{bits}
+    }
+
     #[inline]
     pub fn from_bits(bits: u32) -> Events {
         Events{bits}
@@ -43,6 +64,71 @@ impl Events {
     pub fn bits(&self) -> u32 {
         self.bits
     }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.bits == 0
+    }
+
+    /// Returns true if all of the flags in `other` are set.
+    #[inline]
+    pub fn contains(&self, other: Events) -> bool {
+        (self.bits & other.bits) == other.bits
+    }
+
+    /// Returns true if any of the flags in `other` are set.
+    #[inline]
+    pub fn intersects(&self, other: Events) -> bool {
+        (self.bits & other.bits) != 0
+    }
+
+    #[inline]
+    pub fn insert(&mut self, other: Events) {
+        self.bits |= other.bits;
+    }
+
+    #[inline]
+    pub fn remove(&mut self, other: Events) {
+        self.bits &= !other.bits;
+    }
+
+    /// Returns an iterator over the individual flags that are set; any bits
+    /// that do not correspond to a flag in this module are skipped.
+    pub fn iter(&self) -> impl Iterator<Item=Events> {
+        let this = *self;
+        NAMED_EVENTS.iter().map(|&(ev, _)| ev).filter(move |&ev| this.contains(ev))
+    }
+
+    /// `EPOLLIN` or `EPOLLPRI` is set.
+    #[inline]
+    pub fn is_readable(&self) -> bool {
+        self.intersects(EPOLLIN | EPOLLPRI)
+    }
+
+    /// `EPOLLOUT` is set.
+    #[inline]
+    pub fn is_writable(&self) -> bool {
+        self.intersects(EPOLLOUT)
+    }
+
+    /// `EPOLLHUP` or `EPOLLRDHUP` is set.
+    #[inline]
+    pub fn is_hangup(&self) -> bool {
+        self.intersects(EPOLLHUP | EPOLLRDHUP)
+    }
+
+    /// `EPOLLERR` is set.
+    #[inline]
+    pub fn is_error(&self) -> bool {
+        self.intersects(EPOLLERR)
+    }
+}
+
+impl Default for Events {
+    #[inline]
+    fn default() -> Events {
+        Events::empty()
+    }
 }
 
 impl BitAnd for Events {
@@ -63,6 +149,88 @@ impl BitOr for Events {
     }
 }
 
+impl BitAndAssign for Events {
+    #[inline]
+    fn bitand_assign(&mut self, rhs: Events) {
+        self.bits &= rhs.bits;
+    }
+}
+
+impl BitOrAssign for Events {
+    #[inline]
+    fn bitor_assign(&mut self, rhs: Events) {
+        self.bits |= rhs.bits;
+    }
+}
+
+impl Sub for Events {
+    type Output = Events;
+
+    /// Returns the flags in `self` that are not in `rhs`.
+    #[inline]
+    fn sub(self, rhs: Events) -> Events {
+        Events{bits: self.bits & !rhs.bits}
+    }
+}
+
+impl Not for Events {
+    type Output = Events;
+
+    /// Returns the complement with respect to `Events::all()`.
+    #[inline]
+    fn not(self) -> Events {
+        Events{bits: !self.bits & Events::all().bits}
+    }
+}
+
+impl fmt::Display for Events {
+    /// Formats the set flags by name, e.g. `EPOLLIN | EPOLLRDHUP`; any
+    /// remaining unnamed bits are formatted in hex.
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        if self.is_empty() {
+            return f.write_str("(empty)");
+        }
+        let mut rest = self.bits;
+        let mut first = true;
+        for &(ev, name) in NAMED_EVENTS.iter() {
+            if self.contains(ev) {
+                if !first {
+                    f.write_str(" | ")?;
+                }
+                f.write_str(name)?;
+                rest &= !ev.bits;
+                first = false;
+            }
+        }
+        if rest != 0 {
+            if !first {
+                f.write_str(" | ")?;
+            }
+            write!(f, "{:#x}", rest)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Debug for Events {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Display::fmt(self, f)
+    }
+}
+
+static NAMED_EVENTS: &[(Events, &str)] = &[
+    (EPOLLIN, "EPOLLIN"),
+    (EPOLLPRI, "EPOLLPRI"),
+    (EPOLLOUT, "EPOLLOUT"),
+    (EPOLLERR, "EPOLLERR"),
+    (EPOLLHUP, "EPOLLHUP"),
+    (EPOLLRDHUP, "EPOLLRDHUP"),
+    (EPOLLEXCLUSIVE, "EPOLLEXCLUSIVE"),
+    (EPOLLWAKEUP, "EPOLLWAKEUP"),
+    (EPOLLONESHOT, "EPOLLONESHOT"),
+    (EPOLLET, "EPOLLET"),
+];
+
 /// Sets the Edge Triggered behavior for the associated file descriptor.
 ///
 /// The default behavior for epoll is Level Triggered.
@@ -146,11 +314,18 @@ pub const EPOLLONESHOT: Events = Events{bits: libc::EPOLLONESHOT as u32};
 /// `ctl`; it is never returned by `wait`.
 pub const EPOLLEXCLUSIVE: Events = Events{bits: libc::EPOLLEXCLUSIVE as u32};
 
+/// The event data reserved for wakeup eventfds, such as `eventfd::Waker`
+/// and the shutdown eventfds of the worker pools.
+///
+/// Its low 32 bits are never a valid `Registry` slot, so it may also be
+/// added directly to the `Epoll` of a `Registry`, whose `wait` skips it.
+pub const WAKER_TOKEN: u64 = u64::MAX;
+
 /// 'libc::epoll_event' equivalent.
 ///
 /// SAFETY: This must have the same definition and repr(packed)
 /// as `libc::epoll_event`.
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy)]
 #[cfg_attr(
     any(all(target_arch = "x86",
             not(target_env = "musl"),
@@ -162,6 +337,15 @@ pub struct Event {
     data: u64,
 }
 
+impl fmt::Debug for Event {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        f.debug_struct("Event")
+            .field("events", &self.events())
+            .field("data", &self.raw_data())
+            .finish()
+    }
+}
+
 impl Default for Event {
     #[inline]
     fn default() -> Event {
@@ -194,6 +378,85 @@ impl Event {
     }
 }
 
+/// An owned buffer of ready events, filled by `Epoll::wait_events`.
+///
+/// If a growth limit is set with `grow_up_to`, then whenever a wait fills
+/// the buffer completely the capacity is doubled (up to that limit) for the
+/// next wait, so that bursts of ready file descriptors are drained in fewer
+/// calls.
+#[derive(Clone, Debug)]
+pub struct EventBuffer {
+    buf: Vec<Event>,
+    len: usize,
+    max_capacity: usize,
+}
+
+impl EventBuffer {
+    pub fn with_capacity(capacity: usize) -> EventBuffer {
+        let capacity = capacity.max(1);
+        EventBuffer{
+            buf: vec![Event::default(); capacity],
+            len: 0,
+            max_capacity: capacity,
+        }
+    }
+
+    /// Allows the buffer to grow up to `max_capacity` events.
+    pub fn grow_up_to(mut self, max_capacity: usize) -> EventBuffer {
+        self.max_capacity = max_capacity.max(self.buf.len());
+        self
+    }
+
+    #[inline]
+    pub fn capacity(&self) -> usize {
+        self.buf.len()
+    }
+
+    /// The number of events filled in by the last wait.
+    #[inline]
+    pub fn len(&self) -> usize {
+        self.len
+    }
+
+    #[inline]
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    #[inline]
+    pub fn clear(&mut self) {
+        self.len = 0;
+    }
+
+    #[inline]
+    pub fn as_slice(&self) -> &[Event] {
+        &self.buf[ .. self.len]
+    }
+
+    #[inline]
+    pub fn iter(&self) -> std::iter::Copied<std::slice::Iter<'_, Event>> {
+        self.as_slice().iter().copied()
+    }
+
+    fn set_len(&mut self, len: usize) {
+        self.len = len;
+        let cap = self.buf.len();
+        if len == cap && cap < self.max_capacity {
+            let new_cap = cap.saturating_mul(2).min(self.max_capacity);
+            self.buf.resize(new_cap, Event::default());
+        }
+    }
+}
+
+impl<'a> IntoIterator for &'a EventBuffer {
+    type Item = Event;
+    type IntoIter = std::iter::Copied<std::slice::Iter<'a, Event>>;
+
+    fn into_iter(self) -> Self::IntoIter {
+        self.iter()
+    }
+}
+
 fn cvt(result: libc::c_int) -> io::Result<libc::c_int> {
     if result < 0 {
         Err(Error::last_os_error())
@@ -202,6 +465,27 @@ fn cvt(result: libc::c_int) -> io::Result<libc::c_int> {
     }
 }
 
+/// The error returned by `Epoll::add`, `modify`, `delete`, and `rearm`,
+/// wrapped in an `io::Error` of the same kind as the underlying OS error.
+#[derive(Debug)]
+pub struct CtlError {
+    pub op: &'static str,
+    pub fd: RawFd,
+    pub error: Error,
+}
+
+impl fmt::Display for CtlError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "epoll_ctl({}, fd {}): {}", self.op, self.fd, self.error)
+    }
+}
+
+impl std::error::Error for CtlError {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        Some(&self.error)
+    }
+}
+
 pub struct Epoll {
     epfd: RawFd,
 }
@@ -236,6 +520,42 @@ impl Epoll {
         Ok(())
     }
 
+    fn ctl_named(&self, op: Control, name: &'static str, fd: RawFd, event: Event) -> io::Result<()> {
+        self.ctl(op, fd, event).map_err(|error| {
+            Error::new(error.kind(), CtlError{op: name, fd, error})
+        })
+    }
+
+    /// Adds `fd` to the interest list with the given `events` and `data`.
+    ///
+    /// Errors wrap a `CtlError`, naming the operation and the file descriptor.
+    pub fn add<F: AsRawFd + ?Sized>(&self, fd: &F, events: Events, data: u64) -> io::Result<()> {
+        self.ctl_named(Control::EPOLL_CTL_ADD, "EPOLL_CTL_ADD", fd.as_raw_fd(), Event::new(events, data))
+    }
+
+    /// Changes the `events` and `data` of `fd`, which must already be in the
+    /// interest list.
+    ///
+    /// Errors name the operation and the file descriptor.
+    pub fn modify<F: AsRawFd + ?Sized>(&self, fd: &F, events: Events, data: u64) -> io::Result<()> {
+        self.ctl_named(Control::EPOLL_CTL_MOD, "EPOLL_CTL_MOD", fd.as_raw_fd(), Event::new(events, data))
+    }
+
+    /// Removes `fd` from the interest list.
+    ///
+    /// Errors name the operation and the file descriptor.
+    pub fn delete<F: AsRawFd + ?Sized>(&self, fd: &F) -> io::Result<()> {
+        self.ctl_named(Control::EPOLL_CTL_DEL, "EPOLL_CTL_DEL", fd.as_raw_fd(), Event::default())
+    }
+
+    /// Rearms a `fd` that was registered with `EPOLLONESHOT`, after its event
+    /// has been pulled out with `wait`; `EPOLLONESHOT` is added to `events`.
+    ///
+    /// Errors name the operation and the file descriptor.
+    pub fn rearm<F: AsRawFd + ?Sized>(&self, fd: &F, events: Events, data: u64) -> io::Result<()> {
+        self.modify(fd, events | EPOLLONESHOT, data)
+    }
+
     /// Safe wrapper for `libc::epoll_wait`
     ///
     /// ## Notes
@@ -254,10 +574,137 @@ impl Epoll {
         })? as usize;
         Ok(num_events)
     }
+
+    /// Like `wait`, but with a `Duration` timeout; `None` blocks until an
+    /// event is received.
+    ///
+    /// Sub-millisecond timeouts are rounded up to the next millisecond; use
+    /// `pwait2` for nanosecond precision.
+    pub fn wait_timeout(&self, timeout: Option<Duration>, buf: &mut [Event]) -> io::Result<usize> {
+        self.wait(timeout_millis(timeout), buf)
+    }
+
+    /// Like `wait_timeout`, but fills an owned `EventBuffer`, replacing its
+    /// previous contents.
+    pub fn wait_events(&self, timeout: Option<Duration>, events: &mut EventBuffer) -> io::Result<usize> {
+        events.clear();
+        let n = self.wait_timeout(timeout, &mut events.buf)?;
+        events.set_len(n);
+        Ok(n)
+    }
+
+    /// Safe wrapper for `libc::epoll_pwait`
+    ///
+    /// ## Notes
+    ///
+    /// * If `sigmask` is given, the signal mask is atomically replaced by it
+    ///   for the duration of the call.
+    pub fn pwait(&self, timeout: Option<Duration>, sigmask: Option<&SigSet>, buf: &mut [Event]) -> io::Result<usize> {
+        let epfd = self.epfd;
+        let num_events = cvt(unsafe {
+            libc::epoll_pwait(
+                epfd,
+                buf.as_mut_ptr() as *mut libc::epoll_event,
+                buf.len() as i32,
+                timeout_millis(timeout),
+                sigmask.map_or(null(), |s| s.as_raw()),
+            )
+        })? as usize;
+        Ok(num_events)
+    }
+
+    /// Like `pwait`, but with a nanosecond-precision timeout.
+    ///
+    /// ## Notes
+    ///
+    /// * `epoll_pwait2()` is the underlying syscall (Linux 5.11+). If the
+    ///   running kernel does not support it (which is detected once, on first
+    ///   use), this falls back to `pwait` with the timeout rounded up to
+    ///   the next millisecond.
+    pub fn pwait2(&self, timeout: Option<Duration>, sigmask: Option<&SigSet>, buf: &mut [Event]) -> io::Result<usize> {
+        if PWAIT2_SUPPORT.load(Ordering::Relaxed) == PWAIT2_UNSUPPORTED {
+            return self.pwait(timeout, sigmask, buf);
+        }
+        let epfd = self.epfd;
+        let tspec = timeout.map(to_timespec);
+        let tspec_ptr = tspec.as_ref().map_or(null(), |t| t as *const _);
+        let res = unsafe {
+            libc::syscall(
+                libc::SYS_epoll_pwait2,
+                epfd,
+                buf.as_mut_ptr() as *mut libc::epoll_event,
+                buf.len() as libc::c_int,
+                tspec_ptr,
+                sigmask.map_or(null(), |s| s.as_raw() as *const _),
+                KERNEL_SIGSET_SIZE,
+            )
+        };
+        if res < 0 {
+            let e = Error::last_os_error();
+            if e.raw_os_error() == Some(libc::ENOSYS) {
+                PWAIT2_SUPPORT.store(PWAIT2_UNSUPPORTED, Ordering::Relaxed);
+                return self.pwait(timeout, sigmask, buf);
+            }
+            return Err(e);
+        }
+        PWAIT2_SUPPORT.store(PWAIT2_SUPPORTED, Ordering::Relaxed);
+        Ok(res as usize)
+    }
 }
 
+/// The size of the kernel's `sigset_t` (`_NSIG / 8`), which is smaller than
+/// `libc::sigset_t`; raw syscalls taking a signal mask expect this size.
+#[cfg(not(any(target_arch = "mips", target_arch = "mips32r6", target_arch = "mips64", target_arch = "mips64r6")))]
+const KERNEL_SIGSET_SIZE: libc::size_t = 8;
+
+// NB: `_NSIG` is 128 on MIPS.
+#[cfg(any(target_arch = "mips", target_arch = "mips32r6", target_arch = "mips64", target_arch = "mips64r6"))]
+const KERNEL_SIGSET_SIZE: libc::size_t = 16;
+
+const PWAIT2_UNKNOWN: u8 = 0;
+const PWAIT2_SUPPORTED: u8 = 1;
+const PWAIT2_UNSUPPORTED: u8 = 2;
+
+static PWAIT2_SUPPORT: AtomicU8 = AtomicU8::new(PWAIT2_UNKNOWN);
+
 impl AsRawFd for Epoll {
     fn as_raw_fd(&self) -> RawFd {
         self.epfd
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events_display() {
+        assert_eq!(format!("{}", EPOLLIN | EPOLLRDHUP), "EPOLLIN | EPOLLRDHUP");
+        assert_eq!(format!("{}", EPOLLRDHUP | EPOLLIN), "EPOLLIN | EPOLLRDHUP");
+        assert_eq!(format!("{}", EPOLLET), "EPOLLET");
+        assert_eq!(format!("{}", Events::empty()), "(empty)");
+        // NB: 0x40 is `EPOLLRDNORM`, which has no constant here.
+        assert_eq!(format!("{}", Events::from_bits(0x40)), "0x40");
+        assert_eq!(format!("{}", EPOLLIN | Events::from_bits(0x40)), "EPOLLIN | 0x40");
+        assert_eq!(format!("{:?}", EPOLLIN | EPOLLOUT), "EPOLLIN | EPOLLOUT");
+    }
+
+    #[test]
+    fn events_iter() {
+        let evs: Vec<_> = (EPOLLOUT | EPOLLIN | EPOLLET | Events::from_bits(0x40)).iter().collect();
+        assert_eq!(evs, vec![EPOLLIN, EPOLLOUT, EPOLLET]);
+        assert_eq!(Events::empty().iter().count(), 0);
+        assert_eq!(Events::all().iter().count(), NAMED_EVENTS.len());
+    }
+
+    #[test]
+    fn events_not() {
+        assert_eq!(!EPOLLIN, Events::all() - EPOLLIN);
+        assert_eq!(!Events::empty(), Events::all());
+        assert_eq!(!Events::all(), Events::empty());
+        assert_eq!(!!(EPOLLIN | EPOLLET), EPOLLIN | EPOLLET);
+        // NB: Unnamed bits are not part of `all()`, so they do not survive.
+        assert_eq!(!!Events::from_bits(0x40), Events::empty());
+        assert!(!(!EPOLLIN).contains(EPOLLIN));
+    }
+}
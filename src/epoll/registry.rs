@@ -0,0 +1,259 @@
+use super::{Epoll, Event, Events};
+
+use std::io::{Error, ErrorKind};
+use std::marker::{PhantomData};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration};
+
+/// Identifies a registration in a `Registry`.
+///
+/// The token packs the slab slot index into the low 32 bits and the slot's
+/// generation into the high 32 bits of the epoll `data` word; the generation
+/// is bumped whenever a slot is freed, so a token (or an event) referring to
+/// a previous occupant of the slot is never mistaken for the current one.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Token {
+    slot: u32,
+    gen:  u32,
+}
+
+impl Token {
+    #[inline]
+    pub fn from_u64(data: u64) -> Token {
+        Token{slot: data as u32, gen: (data >> 32) as u32}
+    }
+
+    #[inline]
+    pub fn to_u64(&self) -> u64 {
+        ((self.gen as u64) << 32) | (self.slot as u64)
+    }
+
+    #[inline]
+    pub fn slot(&self) -> u32 {
+        self.slot
+    }
+
+    #[inline]
+    pub fn generation(&self) -> u32 {
+        self.gen
+    }
+}
+
+struct Slot<T> {
+    gen:  u32,
+    // NB: The sequence number of the last `wait` that yielded this slot,
+    // used to guarantee that no slot is yielded twice by one `wait`.
+    seen: u64,
+    item: Option<(RawFd, T)>,
+}
+
+/// An `Epoll` that owns a slab of per-registration values of type `T`.
+pub struct Registry<T> {
+    epoll:  Epoll,
+    slots:  Vec<Slot<T>>,
+    free:   Vec<u32>,
+    buf:    Vec<Event>,
+    seq:    u64,
+}
+
+impl<T> Registry<T> {
+    /// Creates a registry with room for `max_events` events per `wait`.
+    pub fn create(cloexec: bool, max_events: usize) -> Result<Registry<T>, Error> {
+        Ok(Registry{
+            epoll:  Epoll::create(cloexec)?,
+            slots:  Vec::new(),
+            free:   Vec::new(),
+            buf:    vec![Event::default(); max_events.max(1)],
+            seq:    0,
+        })
+    }
+
+    pub fn epoll(&self) -> &Epoll {
+        &self.epoll
+    }
+
+    pub fn len(&self) -> usize {
+        self.slots.len() - self.free.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    fn slot(&self, token: Token) -> Option<&Slot<T>> {
+        match self.slots.get(token.slot as usize) {
+            Some(s) if s.gen == token.gen && s.item.is_some() => Some(s),
+            _ => None,
+        }
+    }
+
+    fn stale(token: Token) -> Error {
+        Error::new(ErrorKind::NotFound, format!("stale or unknown registry token: {:?}", token))
+    }
+
+    /// Adds `fd` to the interest list with the given `events`, storing `value`
+    /// in the slab.
+    pub fn register<F: AsRawFd + ?Sized>(&mut self, fd: &F, events: Events, value: T) -> Result<Token, Error> {
+        let fd = fd.as_raw_fd();
+        let slot = match self.free.pop() {
+            Some(slot) => slot,
+            None => {
+                if self.slots.len() >= u32::MAX as usize {
+                    return Err(Error::other("registry is full"));
+                }
+                self.slots.push(Slot{gen: 0, seen: 0, item: None});
+                (self.slots.len() - 1) as u32
+            }
+        };
+        let token = Token{slot, gen: self.slots[slot as usize].gen};
+        if let Err(e) = self.epoll.add(&fd, events, token.to_u64()) {
+            self.free.push(slot);
+            return Err(e);
+        }
+        self.slots[slot as usize].item = Some((fd, value));
+        Ok(token)
+    }
+
+    /// Changes the `events` of an existing registration.
+    pub fn modify(&mut self, token: Token, events: Events) -> Result<(), Error> {
+        let fd = match self.slot(token) {
+            Some(&Slot{item: Some((fd, _)), ..}) => fd,
+            _ => return Err(Registry::<T>::stale(token)),
+        };
+        self.epoll.modify(&fd, events, token.to_u64())
+    }
+
+    /// Removes a registration from the interest list and returns its value.
+    ///
+    /// The value is returned even if removing the file descriptor from the
+    /// interest list fails (e.g. because it was already closed); the error is
+    /// then discarded.
+    pub fn deregister(&mut self, token: Token) -> Result<T, Error> {
+        if self.slot(token).is_none() {
+            return Err(Registry::<T>::stale(token));
+        }
+        let s = &mut self.slots[token.slot as usize];
+        let (fd, value) = s.item.take().unwrap();
+        s.gen = s.gen.wrapping_add(1);
+        self.free.push(token.slot);
+        let _ = self.epoll.delete(&fd);
+        Ok(value)
+    }
+
+    pub fn get(&self, token: Token) -> Option<&T> {
+        self.slot(token).and_then(|s| s.item.as_ref()).map(|(_, v)| v)
+    }
+
+    pub fn get_mut(&mut self, token: Token) -> Option<&mut T> {
+        self.slot(token)?;
+        self.slots[token.slot as usize].item.as_mut().map(|(_, v)| v)
+    }
+
+    /// Waits for events (see `Epoll::wait_timeout`), and returns an iterator
+    /// over the ready registrations.
+    ///
+    /// Events whose token no longer refers to a live registration are
+    /// dropped.
+    pub fn wait(&mut self, timeout: Option<Duration>) -> Result<Ready<'_, T>, Error> {
+        let n = self.epoll.wait_timeout(timeout, &mut self.buf)?;
+        self.seq += 1;
+        Ok(Ready{
+            slots:  self.slots.as_mut_ptr(),
+            len:    self.slots.len(),
+            events: self.buf[ .. n].iter(),
+            seq:    self.seq,
+            _mrk:   PhantomData,
+        })
+    }
+}
+
+/// The iterator returned by `Registry::wait`.
+pub struct Ready<'a, T> {
+    slots:  *mut Slot<T>,
+    len:    usize,
+    events: std::slice::Iter<'a, Event>,
+    seq:    u64,
+    _mrk:   PhantomData<&'a mut [Slot<T>]>,
+}
+
+impl<'a, T> Iterator for Ready<'a, T> {
+    type Item = (Token, &'a mut T, Events);
+
+    fn next(&mut self) -> Option<(Token, &'a mut T, Events)> {
+        for ev in &mut self.events {
+            let token = Token::from_u64(ev.raw_data());
+            if token.slot as usize >= self.len {
+                continue;
+            }
+            let p = unsafe { self.slots.add(token.slot as usize) };
+            // NB: Check `seen` through the raw pointer, before taking a reference,
+            // since the slot's value may already have been yielded by this `wait`.
+            unsafe {
+                if (*p).gen != token.gen || (*p).seen == self.seq || (*p).item.is_none() {
+                    continue;
+                }
+                (*p).seen = self.seq;
+            }
+            // SAFETY: The slot is in bounds, and the `seen` check above ensures that
+            // each slot is yielded at most once per `wait`, so the returned mutable
+            // references never alias.
+            let s = unsafe { &mut *p };
+            let (_, value) = s.item.as_mut().unwrap();
+            return Some((token, value, ev.events()));
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::epoll::{EPOLLIN};
+    use crate::eventfd::{EFD_CLOEXEC, EFD_NONBLOCK, EventFd};
+
+    fn readable_eventfd() -> EventFd {
+        let efd = EventFd::create(0, EFD_CLOEXEC | EFD_NONBLOCK).unwrap();
+        efd.write(1).unwrap();
+        efd
+    }
+
+    #[test]
+    fn stale_token_after_slot_reuse_is_dropped() {
+        let mut reg = Registry::create(true, 8).unwrap();
+        let (a, b) = (readable_eventfd(), readable_eventfd());
+        let old = reg.register(&a, EPOLLIN, "a").unwrap();
+        assert_eq!(reg.deregister(old).unwrap(), "a");
+        let new = reg.register(&b, EPOLLIN, "b").unwrap();
+        assert_eq!(new.slot(), old.slot());
+        assert_ne!(new.generation(), old.generation());
+        assert!(reg.get(old).is_none());
+        // NB: Deliver an event carrying the old token, as if it had been
+        // queued before the deregistration.
+        reg.epoll().add(&a, EPOLLIN, old.to_u64()).unwrap();
+        let ready: Vec<_> = reg.wait(Some(Duration::from_secs(0))).unwrap()
+            .map(|(token, value, _)| (token, *value))
+            .collect();
+        assert_eq!(ready, vec![(new, "b")]);
+    }
+
+    #[test]
+    fn duplicate_events_for_one_slot_are_yielded_once() {
+        let mut reg = Registry::create(true, 8).unwrap();
+        let (a, b) = (readable_eventfd(), readable_eventfd());
+        let token = reg.register(&a, EPOLLIN, 0).unwrap();
+        reg.epoll().add(&b, EPOLLIN, token.to_u64()).unwrap();
+        // NB: Also an event for a slot that was never allocated.
+        let c = readable_eventfd();
+        reg.epoll().add(&c, EPOLLIN, Token{slot: 100, gen: 0}.to_u64()).unwrap();
+        let mut n = 0;
+        for (t, value, _) in reg.wait(Some(Duration::from_secs(0))).unwrap() {
+            assert_eq!(t, token);
+            *value += 1;
+            n += 1;
+        }
+        assert_eq!(n, 1);
+        assert_eq!(reg.get(token), Some(&1));
+        // NB: The next wait yields the slot again.
+        assert_eq!(reg.wait(Some(Duration::from_secs(0))).unwrap().count(), 1);
+    }
+}
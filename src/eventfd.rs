@@ -0,0 +1,165 @@
+use crate::epoll::{Epoll, EPOLLIN};
+pub use crate::epoll::{WAKER_TOKEN};
+
+use std::io::{Error, ErrorKind};
+use std::ops::{BitOr};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::sync::{Arc};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(transparent)]
+pub struct EventFdFlags {
+  pub bits: libc::c_int,
+}
+
+impl EventFdFlags {
+  #[inline]
+  pub fn empty() -> EventFdFlags {
+    EventFdFlags{bits: 0}
+  }
+
+  #[inline]
+  pub fn bits(&self) -> libc::c_int {
+    self.bits
+  }
+}
+
+impl BitOr for EventFdFlags {
+  type Output = EventFdFlags;
+
+  #[inline]
+  fn bitor(self, rhs: EventFdFlags) -> EventFdFlags {
+    EventFdFlags{bits: self.bits | rhs.bits}
+  }
+}
+
+/// Set `FD_CLOEXEC` on the new file descriptor.
+pub const EFD_CLOEXEC: EventFdFlags = EventFdFlags{bits: libc::EFD_CLOEXEC};
+
+/// Set `O_NONBLOCK` on the new file descriptor: `read` on a zero counter,
+/// and `write` that would overflow the counter, fail with `WouldBlock`.
+pub const EFD_NONBLOCK: EventFdFlags = EventFdFlags{bits: libc::EFD_NONBLOCK};
+
+/// Semaphore-like semantics: `read` returns 1 and decrements the counter by
+/// 1, rather than returning and resetting the whole counter.
+pub const EFD_SEMAPHORE: EventFdFlags = EventFdFlags{bits: libc::EFD_SEMAPHORE};
+
+/// A file descriptor wrapping a kernel-maintained `u64` counter.
+#[derive(Debug)]
+pub struct EventFd {
+  fd: RawFd,
+}
+
+impl Drop for EventFd {
+  fn drop(&mut self) {
+    unsafe { libc::close(self.fd); }
+  }
+}
+
+impl EventFd {
+  /// Creates a new eventfd with the counter set to `initval`.
+  ///
+  /// ## Notes
+  ///
+  /// * `eventfd2()` is the underlying syscall.
+  pub fn create(initval: u32, flags: EventFdFlags) -> Result<EventFd, Error> {
+    let fd = unsafe { libc::eventfd(initval as _, flags.bits()) };
+    if fd < 0 {
+      return Err(Error::last_os_error());
+    }
+    Ok(EventFd{fd})
+  }
+
+  /// Reads the counter (see `EFD_SEMAPHORE`).
+  pub fn read(&self) -> Result<u64, Error> {
+    let mut val: u64 = 0;
+    let res = unsafe { libc::read(self.fd, &mut val as *mut u64 as *mut _, 8) };
+    if res < 0 {
+      return Err(Error::last_os_error());
+    }
+    if res != 8 {
+      return Err(Error::new(ErrorKind::UnexpectedEof, "short eventfd read"));
+    }
+    Ok(val)
+  }
+
+  /// Adds `val` to the counter.
+  pub fn write(&self, val: u64) -> Result<(), Error> {
+    let res = unsafe { libc::write(self.fd, &val as *const u64 as *const _, 8) };
+    if res < 0 {
+      return Err(Error::last_os_error());
+    }
+    if res != 8 {
+      return Err(Error::new(ErrorKind::WriteZero, "short eventfd write"));
+    }
+    Ok(())
+  }
+}
+
+impl AsRawFd for EventFd {
+  fn as_raw_fd(&self) -> RawFd {
+    self.fd
+  }
+}
+
+impl IntoRawFd for EventFd {
+  fn into_raw_fd(self) -> RawFd {
+    let fd = self.fd;
+    std::mem::forget(self);
+    fd
+  }
+}
+
+impl FromRawFd for EventFd {
+  unsafe fn from_raw_fd(fd: RawFd) -> EventFd {
+    EventFd{fd}
+  }
+}
+
+/// Wakes a thread blocked in `Epoll::wait` from any other thread.
+///
+/// The waker is a nonblocking eventfd registered (level-triggered) in the
+/// `Epoll`; `wake` increments its counter, so any number of wakes before the
+/// waiting thread calls `reset` are coalesced into a single event.
+#[derive(Clone, Debug)]
+pub struct Waker {
+  inner: Arc<EventFd>,
+}
+
+impl Waker {
+  /// Creates a waker registered in `epoll` under `WAKER_TOKEN`.
+  pub fn new(epoll: &Epoll) -> Result<Waker, Error> {
+    Waker::with_token(epoll, WAKER_TOKEN)
+  }
+
+  /// Creates a waker registered in `epoll` under `token`.
+  pub fn with_token(epoll: &Epoll, token: u64) -> Result<Waker, Error> {
+    let inner = EventFd::create(0, EFD_CLOEXEC | EFD_NONBLOCK)?;
+    epoll.add(&inner, EPOLLIN, token)?;
+    Ok(Waker{inner: Arc::new(inner)})
+  }
+
+  /// Interrupts the `wait`, or makes the next `wait` return immediately.
+  pub fn wake(&self) -> Result<(), Error> {
+    match self.inner.write(1) {
+      // NB: The counter is saturated, so a wake is already pending.
+      Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+      res => res,
+    }
+  }
+
+  /// Clears pending wakes; to be called by the waiting thread after it
+  /// receives the waker's event.
+  pub fn reset(&self) -> Result<(), Error> {
+    match self.inner.read() {
+      Err(e) if e.kind() == ErrorKind::WouldBlock => Ok(()),
+      res => res.map(|_| ()),
+    }
+  }
+}
+
+impl AsRawFd for Waker {
+  fn as_raw_fd(&self) -> RawFd {
+    self.inner.as_raw_fd()
+  }
+}
@@ -0,0 +1,610 @@
+use std::collections::{HashMap};
+use std::ffi::{CString, OsStr};
+use std::fs;
+use std::io::{Error, ErrorKind};
+use std::mem::{size_of};
+use std::ops::{BitAnd, BitOr};
+use std::os::unix::ffi::{OsStrExt};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::path::{Path, PathBuf};
+use std::ptr::{read_unaligned};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(transparent)]
+pub struct InotifyFlags {
+  pub bits: libc::c_int,
+}
+
+impl InotifyFlags {
+  #[inline]
+  pub fn empty() -> InotifyFlags {
+    InotifyFlags{bits: 0}
+  }
+
+  #[inline]
+  pub fn bits(&self) -> libc::c_int {
+    self.bits
+  }
+}
+
+impl BitOr for InotifyFlags {
+  type Output = InotifyFlags;
+
+  #[inline]
+  fn bitor(self, rhs: InotifyFlags) -> InotifyFlags {
+    InotifyFlags{bits: self.bits | rhs.bits}
+  }
+}
+
+/// Set `FD_CLOEXEC` on the new file descriptor.
+pub const IN_CLOEXEC: InotifyFlags = InotifyFlags{bits: libc::IN_CLOEXEC};
+
+/// Set `O_NONBLOCK` on the new file descriptor.
+pub const IN_NONBLOCK: InotifyFlags = InotifyFlags{bits: libc::IN_NONBLOCK};
+
+/// The events to watch for (in `Inotify::add_watch`), or that occurred (in
+/// `InotifyEvent`).
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(transparent)]
+pub struct WatchMask {
+  pub bits: u32,
+}
+
+impl WatchMask {
+  #[inline]
+  pub fn empty() -> WatchMask {
+    WatchMask{bits: 0}
+  }
+
+  #[inline]
+  pub fn from_bits(bits: u32) -> WatchMask {
+    WatchMask{bits}
+  }
+
+  #[inline]
+  pub fn bits(&self) -> u32 {
+    self.bits
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.bits == 0
+  }
+
+  #[inline]
+  pub fn contains(&self, other: WatchMask) -> bool {
+    (self.bits & other.bits) == other.bits
+  }
+
+  #[inline]
+  pub fn intersects(&self, other: WatchMask) -> bool {
+    (self.bits & other.bits) != 0
+  }
+}
+
+impl BitAnd for WatchMask {
+  type Output = WatchMask;
+
+  #[inline]
+  fn bitand(self, rhs: WatchMask) -> WatchMask {
+    WatchMask{bits: self.bits & rhs.bits}
+  }
+}
+
+impl BitOr for WatchMask {
+  type Output = WatchMask;
+
+  #[inline]
+  fn bitor(self, rhs: WatchMask) -> WatchMask {
+    WatchMask{bits: self.bits | rhs.bits}
+  }
+}
+
+pub const IN_ACCESS: WatchMask = WatchMask{bits: libc::IN_ACCESS};
+pub const IN_MODIFY: WatchMask = WatchMask{bits: libc::IN_MODIFY};
+pub const IN_ATTRIB: WatchMask = WatchMask{bits: libc::IN_ATTRIB};
+pub const IN_CLOSE_WRITE: WatchMask = WatchMask{bits: libc::IN_CLOSE_WRITE};
+pub const IN_CLOSE_NOWRITE: WatchMask = WatchMask{bits: libc::IN_CLOSE_NOWRITE};
+pub const IN_CLOSE: WatchMask = WatchMask{bits: libc::IN_CLOSE};
+pub const IN_OPEN: WatchMask = WatchMask{bits: libc::IN_OPEN};
+pub const IN_MOVED_FROM: WatchMask = WatchMask{bits: libc::IN_MOVED_FROM};
+pub const IN_MOVED_TO: WatchMask = WatchMask{bits: libc::IN_MOVED_TO};
+pub const IN_MOVE: WatchMask = WatchMask{bits: libc::IN_MOVE};
+pub const IN_CREATE: WatchMask = WatchMask{bits: libc::IN_CREATE};
+pub const IN_DELETE: WatchMask = WatchMask{bits: libc::IN_DELETE};
+pub const IN_DELETE_SELF: WatchMask = WatchMask{bits: libc::IN_DELETE_SELF};
+pub const IN_MOVE_SELF: WatchMask = WatchMask{bits: libc::IN_MOVE_SELF};
+pub const IN_ALL_EVENTS: WatchMask = WatchMask{bits: libc::IN_ALL_EVENTS};
+
+/// Only watch the path if it is a directory (for `add_watch`).
+pub const IN_ONLYDIR: WatchMask = WatchMask{bits: libc::IN_ONLYDIR};
+/// Do not follow a symlink at the path (for `add_watch`).
+pub const IN_DONT_FOLLOW: WatchMask = WatchMask{bits: libc::IN_DONT_FOLLOW};
+/// Ignore events for children after they are unlinked (for `add_watch`).
+pub const IN_EXCL_UNLINK: WatchMask = WatchMask{bits: libc::IN_EXCL_UNLINK};
+/// Add to, rather than replace, the mask of an existing watch (for
+/// `add_watch`).
+pub const IN_MASK_ADD: WatchMask = WatchMask{bits: libc::IN_MASK_ADD};
+/// Remove the watch after its first event (for `add_watch`).
+pub const IN_ONESHOT: WatchMask = WatchMask{bits: libc::IN_ONESHOT};
+
+/// The watch was removed, explicitly or because the file was deleted or
+/// unmounted (in events).
+pub const IN_IGNORED: WatchMask = WatchMask{bits: libc::IN_IGNORED};
+/// The subject of the event is a directory (in events).
+pub const IN_ISDIR: WatchMask = WatchMask{bits: libc::IN_ISDIR};
+/// The event queue overflowed, and events were dropped (in events).
+pub const IN_Q_OVERFLOW: WatchMask = WatchMask{bits: libc::IN_Q_OVERFLOW};
+/// The filesystem containing the watched file was unmounted (in events).
+pub const IN_UNMOUNT: WatchMask = WatchMask{bits: libc::IN_UNMOUNT};
+
+/// Identifies a watch in an `Inotify`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct WatchDescriptor(libc::c_int);
+
+impl WatchDescriptor {
+  pub fn from_raw(wd: libc::c_int) -> WatchDescriptor {
+    WatchDescriptor(wd)
+  }
+
+  pub fn to_raw(self) -> libc::c_int {
+    self.0
+  }
+}
+
+/// An event read from an `Inotify`, borrowing its name from the read buffer.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct InotifyEvent<'a> {
+  /// The watch; -1 for `IN_Q_OVERFLOW`.
+  pub wd:     WatchDescriptor,
+  pub mask:   WatchMask,
+  /// Pairs the `IN_MOVED_FROM` and `IN_MOVED_TO` events of a rename.
+  pub cookie: u32,
+  /// The name of the file within a watched directory, if the event is for
+  /// a child rather than the watched file itself.
+  pub name:   Option<&'a OsStr>,
+}
+
+/// An iterator parsing the variable-length `inotify_event` records in a
+/// buffer, without copying.
+#[derive(Clone, Debug)]
+pub struct InotifyEvents<'a> {
+  buf: &'a [u8],
+}
+
+impl<'a> InotifyEvents<'a> {
+  /// Parses `buf`, which should contain whole records as returned by
+  /// `read` on an inotify fd; a truncated trailing record is ignored.
+  pub fn new(buf: &'a [u8]) -> InotifyEvents<'a> {
+    InotifyEvents{buf}
+  }
+}
+
+impl<'a> Iterator for InotifyEvents<'a> {
+  type Item = InotifyEvent<'a>;
+
+  fn next(&mut self) -> Option<InotifyEvent<'a>> {
+    let hdr_len = size_of::<libc::inotify_event>();
+    if self.buf.len() < hdr_len {
+      return None;
+    }
+    // NB: The buffer need not be aligned for `inotify_event`.
+    let raw: libc::inotify_event = unsafe { read_unaligned(self.buf.as_ptr() as *const libc::inotify_event) };
+    let end = hdr_len + raw.len as usize;
+    if self.buf.len() < end {
+      self.buf = &[];
+      return None;
+    }
+    let name = &self.buf[hdr_len .. end];
+    // NB: The name is nul-terminated, and padded with further nuls.
+    let name = match name.iter().position(|&b| b == 0) {
+      Some(n) => &name[ .. n],
+      None => name,
+    };
+    self.buf = &self.buf[end .. ];
+    Some(InotifyEvent{
+      wd:     WatchDescriptor(raw.wd),
+      mask:   WatchMask{bits: raw.mask},
+      cookie: raw.cookie,
+      name:   if name.is_empty() { None } else { Some(OsStr::from_bytes(name)) },
+    })
+  }
+}
+
+/// A file descriptor receiving filesystem events, which becomes readable
+/// (e.g. `EPOLLIN` in an `Epoll`) when events are pending.
+#[derive(Debug)]
+pub struct Inotify {
+  fd: RawFd,
+}
+
+impl Drop for Inotify {
+  fn drop(&mut self) {
+    unsafe { libc::close(self.fd); }
+  }
+}
+
+impl Inotify {
+  /// ## Notes
+  ///
+  /// * `inotify_init1()` is the underlying syscall.
+  pub fn init(flags: InotifyFlags) -> Result<Inotify, Error> {
+    let fd = unsafe { libc::inotify_init1(flags.bits()) };
+    if fd < 0 {
+      return Err(Error::last_os_error());
+    }
+    Ok(Inotify{fd})
+  }
+
+  /// Watches `path` for the events in `mask`; watching the same file again
+  /// returns the same descriptor, and replaces its mask (unless
+  /// `IN_MASK_ADD`).
+  ///
+  /// ## Notes
+  ///
+  /// * `inotify_add_watch()` is the underlying syscall.
+  pub fn add_watch<P: AsRef<Path>>(&self, path: P, mask: WatchMask) -> Result<WatchDescriptor, Error> {
+    let path = CString::new(path.as_ref().as_os_str().as_bytes())
+      .map_err(|_| Error::new(ErrorKind::InvalidInput, "path contains a nul byte"))?;
+    let wd = unsafe { libc::inotify_add_watch(self.fd, path.as_ptr(), mask.bits()) };
+    if wd < 0 {
+      return Err(Error::last_os_error());
+    }
+    Ok(WatchDescriptor(wd))
+  }
+
+  /// Removes a watch; an `IN_IGNORED` event is generated for it.
+  ///
+  /// ## Notes
+  ///
+  /// * `inotify_rm_watch()` is the underlying syscall.
+  pub fn rm_watch(&self, wd: WatchDescriptor) -> Result<(), Error> {
+    let res = unsafe { libc::inotify_rm_watch(self.fd, wd.0) };
+    if res != 0 {
+      return Err(Error::last_os_error());
+    }
+    Ok(())
+  }
+
+  /// Reads pending events into `buf`, blocking until there is at least one
+  /// if the inotify is not `IN_NONBLOCK`.
+  ///
+  /// `buf` should have room for at least one event with a maximal name,
+  /// i.e. `size_of::<libc::inotify_event>() + NAME_MAX + 1` bytes, or else
+  /// this fails with `EINVAL`.
+  pub fn read_events<'a>(&self, buf: &'a mut [u8]) -> Result<InotifyEvents<'a>, Error> {
+    let res = unsafe { libc::read(self.fd, buf.as_mut_ptr() as *mut _, buf.len()) };
+    if res < 0 {
+      return Err(Error::last_os_error());
+    }
+    Ok(InotifyEvents::new(&buf[ .. res as usize]))
+  }
+}
+
+impl AsRawFd for Inotify {
+  fn as_raw_fd(&self) -> RawFd {
+    self.fd
+  }
+}
+
+impl IntoRawFd for Inotify {
+  fn into_raw_fd(self) -> RawFd {
+    let fd = self.fd;
+    std::mem::forget(self);
+    fd
+  }
+}
+
+impl FromRawFd for Inotify {
+  unsafe fn from_raw_fd(fd: RawFd) -> Inotify {
+    Inotify{fd}
+  }
+}
+
+/// An event from a `RecursiveWatcher`.
+#[derive(Clone, PartialEq, Eq, Debug)]
+pub enum WatchEvent {
+  /// An event (filtered by the watcher's mask) for `path`.
+  Event{path: PathBuf, mask: WatchMask},
+  /// A file or directory was renamed within the watched tree.
+  Renamed{from: PathBuf, to: PathBuf, is_dir: bool},
+  /// Events were dropped; the caller should rescan the tree.
+  Overflow,
+  /// A new directory (or one below it) could not be watched, e.g. with
+  /// `ENOSPC` once `max_user_watches` is reached, so events under `path`
+  /// may be missed; the caller should rescan it.
+  Error{path: PathBuf, errno: libc::c_int},
+}
+
+// NB: The events that the watcher always needs, to track the tree.
+const TREE_EVENTS: WatchMask = WatchMask{bits: libc::IN_CREATE | libc::IN_MOVED_FROM | libc::IN_MOVED_TO};
+
+/// Watches a directory tree, adding watches for new subdirectories as they
+/// appear, and pairing the halves of renames within the tree.
+///
+/// The watcher is nonblocking, and may be registered (via `AsRawFd`) in an
+/// `Epoll` for `EPOLLIN`, calling `read_events` when ready.
+pub struct RecursiveWatcher {
+  inotify:  Inotify,
+  mask:     WatchMask,
+  dirs:     HashMap<WatchDescriptor, PathBuf>,
+  buf:      Vec<u8>,
+}
+
+impl RecursiveWatcher {
+  /// Watches `root` and every directory below it, reporting the events in
+  /// `mask` (renames are always reported).
+  pub fn new<P: AsRef<Path>>(root: P, mask: WatchMask) -> Result<RecursiveWatcher, Error> {
+    let mut w = RecursiveWatcher{
+      inotify:  Inotify::init(IN_CLOEXEC | IN_NONBLOCK)?,
+      mask,
+      dirs:     HashMap::new(),
+      buf:      vec![0; 64 * 1024],
+    };
+    w.add_tree(root.as_ref(), None)?;
+    Ok(w)
+  }
+
+  /// The directories currently watched.
+  pub fn watched_dirs(&self) -> impl Iterator<Item=&Path> {
+    self.dirs.values().map(|p| p.as_path())
+  }
+
+  /// Watches `dir` and its subdirectories; if `created` is given, also
+  /// reports their existing contents as created, since they may have
+  /// appeared before the watches were added.
+  fn add_tree(&mut self, dir: &Path, mut created: Option<&mut Vec<WatchEvent>>) -> Result<(), Error> {
+    let mask = self.mask | TREE_EVENTS | IN_ONLYDIR | IN_DONT_FOLLOW;
+    let wd = self.inotify.add_watch(dir, mask)?;
+    self.dirs.insert(wd, dir.to_owned());
+    for entry in fs::read_dir(dir)? {
+      let entry = entry?;
+      let is_dir = entry.file_type()?.is_dir();
+      let path = entry.path();
+      if let Some(out) = created.as_mut() {
+        if self.mask.contains(IN_CREATE) {
+          let mask = if is_dir { IN_CREATE | IN_ISDIR } else { IN_CREATE };
+          out.push(WatchEvent::Event{path: path.clone(), mask});
+        }
+      }
+      if is_dir {
+        match created.as_deref_mut() {
+          Some(out) => self.add_new_tree(&path, out),
+          None => match self.add_tree(&path, None) {
+            // NB: The directory may have been removed in the meantime.
+            Err(e) if e.kind() == ErrorKind::NotFound => {}
+            res => res?,
+          },
+        }
+      }
+    }
+    Ok(())
+  }
+
+  /// Like `add_tree` for a directory that appeared after the watcher was
+  /// created; failures are reported in `out`, since the events read so far
+  /// must not be lost.
+  fn add_new_tree(&mut self, dir: &Path, out: &mut Vec<WatchEvent>) {
+    match self.add_tree(dir, Some(out)) {
+      Ok(()) => {}
+      // NB: The directory may have been removed in the meantime.
+      Err(e) if e.kind() == ErrorKind::NotFound => {}
+      Err(e) => out.push(WatchEvent::Error{
+        path:   dir.to_owned(),
+        errno:  e.raw_os_error().unwrap_or(libc::EIO),
+      }),
+    }
+  }
+
+  /// Rebases the watched directories under `from` onto `to`; returns
+  /// false if `from` itself was not watched.
+  fn rename_dirs(&mut self, from: &Path, to: &Path) -> bool {
+    let mut found = false;
+    for path in self.dirs.values_mut() {
+      let rebased = match path.strip_prefix(from) {
+        Ok(rest) => to.join(rest),
+        Err(_) => continue,
+      };
+      found |= path.as_path() == from;
+      *path = rebased;
+    }
+    found
+  }
+
+  /// Reads and decodes all pending events, without blocking; returns an
+  /// empty list if there are none.
+  ///
+  /// Failures to watch new directories are reported as `WatchEvent::Error`
+  /// rather than failing the read, which would lose the events already
+  /// consumed from the kernel.
+  pub fn read_events(&mut self) -> Result<Vec<WatchEvent>, Error> {
+    let mut out = Vec::new();
+    let mut buf = std::mem::take(&mut self.buf);
+    let res = self.read_events_into(&mut buf, &mut out);
+    self.buf = buf;
+    res.map(|_| out)
+  }
+
+  fn read_events_into(&mut self, buf: &mut [u8], out: &mut Vec<WatchEvent>) -> Result<(), Error> {
+    // NB: A rename within the tree is a `IN_MOVED_FROM`, immediately
+    // followed by a `IN_MOVED_TO` with the same cookie; a `IN_MOVED_FROM`
+    // left unpaired at the end of a read was moved out of the tree.
+    let mut moved_from: Option<(u32, PathBuf, bool)> = None;
+    loop {
+      let events = match self.inotify.read_events(buf) {
+        Ok(events) => events,
+        Err(e) if e.kind() == ErrorKind::WouldBlock => break,
+        Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+        Err(e) => return Err(e),
+      };
+      for ev in events {
+        if ev.mask.contains(IN_Q_OVERFLOW) {
+          out.push(WatchEvent::Overflow);
+          continue;
+        }
+        if ev.mask.contains(IN_IGNORED) {
+          self.dirs.remove(&ev.wd);
+          continue;
+        }
+        let dir = match self.dirs.get(&ev.wd) {
+          Some(dir) => dir,
+          None => continue,
+        };
+        let path = match ev.name {
+          Some(name) => dir.join(name),
+          None => dir.clone(),
+        };
+        let is_dir = ev.mask.contains(IN_ISDIR);
+        if let Some((cookie, from, from_is_dir)) = moved_from.take() {
+          if ev.mask.contains(IN_MOVED_TO) && ev.cookie == cookie {
+            // NB: If the directory was created and renamed before its
+            // creation was read, it is not watched yet.
+            if from_is_dir && !self.rename_dirs(&from, &path) {
+              self.add_new_tree(&path, out);
+            }
+            out.push(WatchEvent::Renamed{from, to: path, is_dir: from_is_dir});
+            continue;
+          }
+          self.moved_out(from, from_is_dir, out);
+        }
+        if ev.mask.contains(IN_MOVED_FROM) {
+          moved_from = Some((ev.cookie, path, is_dir));
+          continue;
+        }
+        let mask = ev.mask & (self.mask | IN_ISDIR);
+        if mask.intersects(self.mask) {
+          out.push(WatchEvent::Event{path: path.clone(), mask});
+        }
+        if is_dir && ev.mask.intersects(IN_CREATE | IN_MOVED_TO) {
+          self.add_new_tree(&path, out);
+        }
+      }
+    }
+    if let Some((_, from, from_is_dir)) = moved_from.take() {
+      self.moved_out(from, from_is_dir, out);
+    }
+    Ok(())
+  }
+
+  fn moved_out(&mut self, from: PathBuf, is_dir: bool, out: &mut Vec<WatchEvent>) {
+    if is_dir {
+      // NB: The watches follow the moved directories; stop watching them,
+      // since they are no longer in the tree.
+      let gone: Vec<_> = self.dirs.iter()
+        .filter(|&(_, p)| p.starts_with(&from))
+        .map(|(&wd, _)| wd)
+        .collect();
+      for wd in gone {
+        let _ = self.inotify.rm_watch(wd);
+        self.dirs.remove(&wd);
+      }
+    }
+    if self.mask.contains(IN_MOVED_FROM) {
+      let mask = if is_dir { IN_MOVED_FROM | IN_ISDIR } else { IN_MOVED_FROM };
+      out.push(WatchEvent::Event{path: from, mask});
+    }
+  }
+}
+
+impl AsRawFd for RecursiveWatcher {
+  fn as_raw_fd(&self) -> RawFd {
+    self.inotify.as_raw_fd()
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  fn record(wd: i32, mask: u32, cookie: u32, name: &[u8], len: usize) -> Vec<u8> {
+    let mut buf = Vec::new();
+    buf.extend_from_slice(&wd.to_ne_bytes());
+    buf.extend_from_slice(&mask.to_ne_bytes());
+    buf.extend_from_slice(&cookie.to_ne_bytes());
+    buf.extend_from_slice(&(len as u32).to_ne_bytes());
+    let mut padded = name.to_owned();
+    padded.resize(len, 0);
+    buf.extend_from_slice(&padded);
+    buf
+  }
+
+  #[test]
+  fn parse_records() {
+    let mut buf = vec![0xff];
+    buf.extend(record(1, libc::IN_CREATE, 0, b"", 0));
+    buf.extend(record(2, libc::IN_MOVED_FROM, 7, b"a.txt", 16));
+    buf.extend(record(2, libc::IN_MOVED_TO, 7, b"0123456789abcdef", 16));
+    // NB: Skip the first byte, so that the records are misaligned.
+    let events: Vec<_> = InotifyEvents::new(&buf[1 .. ]).collect();
+    assert_eq!(events.len(), 3);
+    assert_eq!(events[0].wd, WatchDescriptor(1));
+    assert_eq!(events[0].mask, IN_CREATE);
+    assert_eq!(events[0].name, None);
+    assert_eq!(events[1].mask, IN_MOVED_FROM);
+    assert_eq!(events[1].cookie, 7);
+    assert_eq!(events[1].name, Some(OsStr::new("a.txt")));
+    assert_eq!(events[2].name, Some(OsStr::new("0123456789abcdef")));
+  }
+
+  #[test]
+  fn parse_truncated_tail() {
+    let mut buf = record(1, libc::IN_MODIFY, 0, b"x", 16);
+    let full = buf.len();
+    buf.extend(record(2, libc::IN_DELETE, 0, b"y", 16));
+    // NB: Truncated within the second record's name, and within its header.
+    for &cut in [full + 20, full + 8, full].iter() {
+      let events: Vec<_> = InotifyEvents::new(&buf[ .. cut]).collect();
+      assert_eq!(events.len(), 1);
+      assert_eq!(events[0].name, Some(OsStr::new("x")));
+    }
+    assert_eq!(InotifyEvents::new(&buf[ .. full - 1]).count(), 0);
+  }
+
+  #[test]
+  fn recursive_watcher_renames_and_new_dirs() {
+    let root = std::env::temp_dir().join(format!("unix2-inotify-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(root.join("a")).unwrap();
+    let mut w = RecursiveWatcher::new(&root, IN_CREATE | IN_MOVE).unwrap();
+
+    fs::create_dir_all(root.join("a/new")).unwrap();
+    let events = w.read_events().unwrap();
+    assert!(events.contains(&WatchEvent::Event{path: root.join("a/new"), mask: IN_CREATE | IN_ISDIR}));
+    assert!(w.watched_dirs().any(|p| p == root.join("a/new")));
+
+    fs::write(root.join("a/new/f"), b"").unwrap();
+    fs::rename(root.join("a/new/f"), root.join("a/g")).unwrap();
+    let events = w.read_events().unwrap();
+    assert!(events.contains(&WatchEvent::Event{path: root.join("a/new/f"), mask: IN_CREATE}));
+    assert!(events.contains(&WatchEvent::Renamed{from: root.join("a/new/f"), to: root.join("a/g"), is_dir: false}));
+
+    fs::rename(root.join("a/new"), root.join("b")).unwrap();
+    fs::write(root.join("b/h"), b"").unwrap();
+    let events = w.read_events().unwrap();
+    assert!(events.contains(&WatchEvent::Renamed{from: root.join("a/new"), to: root.join("b"), is_dir: true}));
+    assert!(events.contains(&WatchEvent::Event{path: root.join("b/h"), mask: IN_CREATE}));
+
+    fs::remove_dir_all(&root).unwrap();
+  }
+
+  #[test]
+  fn recursive_watcher_reports_unwatchable_dirs_in_band() {
+    let root = std::env::temp_dir().join(format!("unix2-inotify-err-{}", std::process::id()));
+    let _ = fs::remove_dir_all(&root);
+    fs::create_dir_all(&root).unwrap();
+    let mut w = RecursiveWatcher::new(&root, IN_CREATE).unwrap();
+
+    // NB: By the time the creation of `d` is read, it is a file, so adding
+    // a watch for it fails with `ENOTDIR`.
+    fs::create_dir(root.join("d")).unwrap();
+    fs::remove_dir(root.join("d")).unwrap();
+    fs::write(root.join("d"), b"").unwrap();
+    fs::write(root.join("e"), b"").unwrap();
+    let events = w.read_events().unwrap();
+    assert!(events.contains(&WatchEvent::Event{path: root.join("d"), mask: IN_CREATE | IN_ISDIR}));
+    assert!(events.contains(&WatchEvent::Error{path: root.join("d"), errno: libc::ENOTDIR}));
+    assert!(events.contains(&WatchEvent::Event{path: root.join("e"), mask: IN_CREATE}));
+
+    fs::remove_dir_all(&root).unwrap();
+  }
+}
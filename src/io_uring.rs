@@ -0,0 +1,659 @@
+//! A completion-based alternative to `Epoll`, over `io_uring`.
+//!
+//! Submission entries (`Sqe`) are built from raw pointers, and pushed to
+//! the submission queue with the unsafe `IoUring::push`: the caller must
+//! keep the pointed-to buffers (and, for `timeout`, `openat`, and `statx`,
+//! the arguments) alive and unaliased until the matching completion (`Cqe`)
+//! is reaped. Completions are matched to submissions by `user_data`.
+//!
+//! Kernels without `io_uring` (or with it disabled) fail `IoUring::new`
+//! with `ENOSYS` or `EPERM`; callers may also use `IoUring::probe` to check
+//! for specific opcodes, and fall back to `Epoll` otherwise.
+
+use crate::mode::{Mode};
+use crate::poll::{PollFlags};
+
+use std::io::{Error, ErrorKind};
+use std::mem::{size_of, zeroed};
+use std::ops::{BitOr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::ptr::{null_mut};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::time::{Duration};
+
+const IORING_OFF_SQ_RING: libc::off_t = 0;
+const IORING_OFF_CQ_RING: libc::off_t = 0x0800_0000;
+const IORING_OFF_SQES: libc::off_t = 0x1000_0000;
+
+const IORING_ENTER_GETEVENTS: u32 = 1 << 0;
+
+const IORING_REGISTER_PROBE: u32 = 8;
+
+const IO_URING_OP_SUPPORTED: u16 = 1 << 0;
+
+/// The SQ and CQ rings share a single mapping.
+pub const IORING_FEAT_SINGLE_MMAP: u32 = 1 << 0;
+/// Completions are never dropped when the CQ ring overflows.
+pub const IORING_FEAT_NODROP: u32 = 1 << 1;
+/// Data for submissions is stable once consumed by `io_uring_enter`.
+pub const IORING_FEAT_SUBMIT_STABLE: u32 = 1 << 2;
+/// Reads and writes with offset `u64::MAX` use the current file position.
+pub const IORING_FEAT_RW_CUR_POS: u32 = 1 << 3;
+/// Poll-based retry is used for pollable files instead of worker threads.
+pub const IORING_FEAT_FAST_POLL: u32 = 1 << 5;
+
+/// Interpret the timespec of a `timeout` as absolute (on `CLOCK_MONOTONIC`).
+pub const IORING_TIMEOUT_ABS: u32 = 1 << 0;
+
+/// The offset for `read`, `write`, `readv` and `writev` which uses (and
+/// advances) the current file position.
+pub const CURRENT_POS: u64 = u64::MAX;
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+struct SqringOffsets {
+  head:         u32,
+  tail:         u32,
+  ring_mask:    u32,
+  ring_entries: u32,
+  flags:        u32,
+  dropped:      u32,
+  array:        u32,
+  resv1:        u32,
+  user_addr:    u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+struct CqringOffsets {
+  head:         u32,
+  tail:         u32,
+  ring_mask:    u32,
+  ring_entries: u32,
+  overflow:     u32,
+  cqes:         u32,
+  flags:        u32,
+  resv1:        u32,
+  user_addr:    u64,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy, Default, Debug)]
+struct Params {
+  sq_entries:     u32,
+  cq_entries:     u32,
+  flags:          u32,
+  sq_thread_cpu:  u32,
+  sq_thread_idle: u32,
+  features:       u32,
+  wq_fd:          u32,
+  resv:           [u32; 3],
+  sq_off:         SqringOffsets,
+  cq_off:         CqringOffsets,
+}
+
+#[repr(C)]
+#[derive(Clone, Copy)]
+struct RawProbeOp {
+  op:     u8,
+  resv:   u8,
+  flags:  u16,
+  resv2:  u32,
+}
+
+#[repr(C)]
+struct RawProbe {
+  last_op:  u8,
+  ops_len:  u8,
+  resv:     u16,
+  resv2:    [u32; 3],
+  ops:      [RawProbeOp; 256],
+}
+
+/// The `io_uring` opcodes with typed `Sqe` constructors.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(u8)]
+pub enum Opcode {
+  Nop     = 0,
+  Readv   = 1,
+  Writev  = 2,
+  PollAdd = 6,
+  Timeout = 11,
+  Accept  = 13,
+  Connect = 16,
+  Openat  = 18,
+  Close   = 19,
+  Statx   = 21,
+  Read    = 22,
+  Write   = 23,
+  Send    = 26,
+  Recv    = 27,
+}
+
+/// The opcodes supported by the running kernel, from `IoUring::probe`.
+#[derive(Clone, Debug)]
+pub struct Probe {
+  last_op:  u8,
+  flags:    Vec<u16>,
+}
+
+impl Probe {
+  /// The highest opcode known to the kernel.
+  pub fn last_op(&self) -> u8 {
+    self.last_op
+  }
+
+  pub fn is_supported(&self, op: Opcode) -> bool {
+    match self.flags.get(op as usize) {
+      Some(&flags) => (flags & IO_URING_OP_SUPPORTED) != 0,
+      None => false,
+    }
+  }
+
+  /// Whether all of `ops` are supported; if not, the caller should fall
+  /// back to `Epoll`.
+  pub fn supports_all(&self, ops: &[Opcode]) -> bool {
+    ops.iter().all(|&op| self.is_supported(op))
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(transparent)]
+pub struct SqeFlags {
+  pub bits: u8,
+}
+
+impl SqeFlags {
+  #[inline]
+  pub fn empty() -> SqeFlags {
+    SqeFlags{bits: 0}
+  }
+
+  #[inline]
+  pub fn bits(&self) -> u8 {
+    self.bits
+  }
+}
+
+impl BitOr for SqeFlags {
+  type Output = SqeFlags;
+
+  #[inline]
+  fn bitor(self, rhs: SqeFlags) -> SqeFlags {
+    SqeFlags{bits: self.bits | rhs.bits}
+  }
+}
+
+/// The fd is an index into the registered file table.
+pub const IOSQE_FIXED_FILE: SqeFlags = SqeFlags{bits: 1 << 0};
+/// Start only after all previous submissions have completed.
+pub const IOSQE_IO_DRAIN: SqeFlags = SqeFlags{bits: 1 << 1};
+/// Start the next submission only after this one completes successfully.
+pub const IOSQE_IO_LINK: SqeFlags = SqeFlags{bits: 1 << 2};
+/// Like `IOSQE_IO_LINK`, but the link is not broken by failure.
+pub const IOSQE_IO_HARDLINK: SqeFlags = SqeFlags{bits: 1 << 3};
+/// Always issue from a worker thread, without a nonblocking attempt first.
+pub const IOSQE_ASYNC: SqeFlags = SqeFlags{bits: 1 << 4};
+
+/// A `__kernel_timespec`, for `Sqe::timeout`.
+#[repr(C)]
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct Timespec {
+  pub tv_sec:   i64,
+  pub tv_nsec:  i64,
+}
+
+impl From<Duration> for Timespec {
+  fn from(d: Duration) -> Timespec {
+    Timespec{
+      tv_sec:   d.as_secs().min(i64::MAX as u64) as i64,
+      tv_nsec:  d.subsec_nanos() as i64,
+    }
+  }
+}
+
+/// A submission queue entry (`struct io_uring_sqe`).
+#[repr(C)]
+#[derive(Clone, Copy, Debug)]
+pub struct Sqe {
+  opcode:       u8,
+  flags:        u8,
+  ioprio:       u16,
+  fd:           i32,
+  off:          u64,
+  addr:         u64,
+  len:          u32,
+  op_flags:     u32,
+  user_data:    u64,
+  buf_index:    u16,
+  personality:  u16,
+  splice_fd_in: i32,
+  addr3:        u64,
+  pad:          u64,
+}
+
+impl Sqe {
+  fn new(op: Opcode, fd: RawFd) -> Sqe {
+    let mut sqe: Sqe = unsafe { zeroed() };
+    sqe.opcode = op as u8;
+    sqe.fd = fd;
+    sqe
+  }
+
+  pub fn nop() -> Sqe {
+    Sqe::new(Opcode::Nop, -1)
+  }
+
+  /// Reads up to `len` bytes into `buf`, at `offset` (or `CURRENT_POS`).
+  pub fn read(fd: RawFd, buf: *mut u8, len: u32, offset: u64) -> Sqe {
+    let mut sqe = Sqe::new(Opcode::Read, fd);
+    sqe.addr = buf as u64;
+    sqe.len = len;
+    sqe.off = offset;
+    sqe
+  }
+
+  /// Writes up to `len` bytes from `buf`, at `offset` (or `CURRENT_POS`).
+  pub fn write(fd: RawFd, buf: *const u8, len: u32, offset: u64) -> Sqe {
+    let mut sqe = Sqe::new(Opcode::Write, fd);
+    sqe.addr = buf as u64;
+    sqe.len = len;
+    sqe.off = offset;
+    sqe
+  }
+
+  pub fn readv(fd: RawFd, iovecs: *const libc::iovec, iovcnt: u32, offset: u64) -> Sqe {
+    let mut sqe = Sqe::new(Opcode::Readv, fd);
+    sqe.addr = iovecs as u64;
+    sqe.len = iovcnt;
+    sqe.off = offset;
+    sqe
+  }
+
+  pub fn writev(fd: RawFd, iovecs: *const libc::iovec, iovcnt: u32, offset: u64) -> Sqe {
+    let mut sqe = Sqe::new(Opcode::Writev, fd);
+    sqe.addr = iovecs as u64;
+    sqe.len = iovcnt;
+    sqe.off = offset;
+    sqe
+  }
+
+  /// Accepts a connection; the result is the new fd. `addr` and `addrlen`
+  /// may be null; `flags` are as for `accept4` (e.g. `SOCK_CLOEXEC`).
+  pub fn accept(fd: RawFd, addr: *mut libc::sockaddr, addrlen: *mut libc::socklen_t, flags: libc::c_int) -> Sqe {
+    let mut sqe = Sqe::new(Opcode::Accept, fd);
+    sqe.addr = addr as u64;
+    sqe.off = addrlen as u64;
+    sqe.op_flags = flags as u32;
+    sqe
+  }
+
+  pub fn connect(fd: RawFd, addr: *const libc::sockaddr, addrlen: libc::socklen_t) -> Sqe {
+    let mut sqe = Sqe::new(Opcode::Connect, fd);
+    sqe.addr = addr as u64;
+    sqe.off = addrlen as u64;
+    sqe
+  }
+
+  /// Sends up to `len` bytes from `buf`; `flags` are as for `send` (e.g.
+  /// `MSG_NOSIGNAL`).
+  pub fn send(fd: RawFd, buf: *const u8, len: u32, flags: libc::c_int) -> Sqe {
+    let mut sqe = Sqe::new(Opcode::Send, fd);
+    sqe.addr = buf as u64;
+    sqe.len = len;
+    sqe.op_flags = flags as u32;
+    sqe
+  }
+
+  pub fn recv(fd: RawFd, buf: *mut u8, len: u32, flags: libc::c_int) -> Sqe {
+    let mut sqe = Sqe::new(Opcode::Recv, fd);
+    sqe.addr = buf as u64;
+    sqe.len = len;
+    sqe.op_flags = flags as u32;
+    sqe
+  }
+
+  /// Completes with `ETIME` once `ts` elapses, or with 0 once `count` other
+  /// completions have been posted (if `count` is nonzero). `flags` may be
+  /// `IORING_TIMEOUT_ABS`.
+  pub fn timeout(ts: *const Timespec, count: u32, flags: u32) -> Sqe {
+    let mut sqe = Sqe::new(Opcode::Timeout, -1);
+    sqe.addr = ts as u64;
+    sqe.len = 1;
+    sqe.off = count as u64;
+    sqe.op_flags = flags;
+    sqe
+  }
+
+  /// Completes, once, with the ready events (as `PollFlags` bits) when `fd`
+  /// is ready for any of `events`.
+  pub fn poll_add(fd: RawFd, events: PollFlags) -> Sqe {
+    let mut sqe = Sqe::new(Opcode::PollAdd, fd);
+    sqe.op_flags = (events.bits as u16) as u32;
+    sqe
+  }
+
+  pub fn close(fd: RawFd) -> Sqe {
+    Sqe::new(Opcode::Close, fd)
+  }
+
+  /// Opens `path` (a nul-terminated string) relative to `dirfd` (or
+  /// `libc::AT_FDCWD`); the result is the new fd.
+  pub fn openat(dirfd: RawFd, path: *const libc::c_char, flags: libc::c_int, mode: Mode) -> Sqe {
+    let mut sqe = Sqe::new(Opcode::Openat, dirfd);
+    sqe.addr = path as u64;
+    sqe.len = mode.bits;
+    sqe.op_flags = flags as u32;
+    sqe
+  }
+
+  /// Stats `path` (a nul-terminated string) relative to `dirfd` into
+  /// `statxbuf`; `flags` are `AT_*` flags, `mask` is `STATX_*` fields.
+  pub fn statx(dirfd: RawFd, path: *const libc::c_char, flags: libc::c_int, mask: u32, statxbuf: *mut libc::statx) -> Sqe {
+    let mut sqe = Sqe::new(Opcode::Statx, dirfd);
+    sqe.addr = path as u64;
+    sqe.len = mask;
+    sqe.off = statxbuf as u64;
+    sqe.op_flags = flags as u32;
+    sqe
+  }
+
+  pub fn user_data(mut self, user_data: u64) -> Sqe {
+    self.user_data = user_data;
+    self
+  }
+
+  pub fn flags(mut self, flags: SqeFlags) -> Sqe {
+    self.flags = flags.bits;
+    self
+  }
+
+  pub fn opcode(&self) -> u8 {
+    self.opcode
+  }
+
+  pub fn get_user_data(&self) -> u64 {
+    self.user_data
+  }
+}
+
+/// A completion queue entry (`struct io_uring_cqe`).
+#[repr(C)]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct Cqe {
+  pub user_data:  u64,
+  /// The result of the operation (as for the equivalent syscall), or a
+  /// negated errno.
+  pub res:        i32,
+  pub flags:      u32,
+}
+
+impl Cqe {
+  /// Converts `res` to a result, e.g. the number of bytes transferred or
+  /// the new fd.
+  pub fn result(&self) -> Result<u32, Error> {
+    if self.res < 0 {
+      return Err(Error::from_raw_os_error(-self.res));
+    }
+    Ok(self.res as u32)
+  }
+}
+
+struct Mmap {
+  ptr:  *mut u8,
+  len:  usize,
+}
+
+impl Drop for Mmap {
+  fn drop(&mut self) {
+    unsafe { libc::munmap(self.ptr as *mut _, self.len); }
+  }
+}
+
+impl Mmap {
+  fn map(fd: RawFd, len: usize, offset: libc::off_t) -> Result<Mmap, Error> {
+    let ptr = unsafe { libc::mmap(
+        null_mut(), len,
+        libc::PROT_READ | libc::PROT_WRITE, libc::MAP_SHARED | libc::MAP_POPULATE,
+        fd, offset,
+    ) };
+    if ptr == libc::MAP_FAILED {
+      return Err(Error::last_os_error());
+    }
+    Ok(Mmap{ptr: ptr as *mut u8, len})
+  }
+
+  unsafe fn at<T>(&self, offset: u32) -> *mut T {
+    self.ptr.add(offset as usize) as *mut T
+  }
+}
+
+/// An `io_uring` instance, with its mmapped submission and completion
+/// queues.
+pub struct IoUring {
+  fd:         RawFd,
+  features:   u32,
+  sq_head:    *const AtomicU32,
+  sq_tail:    *const AtomicU32,
+  sq_mask:    u32,
+  sq_entries: u32,
+  sq_array:   *mut u32,
+  sqes:       *mut Sqe,
+  // NB: The number of entries pushed, but not yet consumed by the kernel.
+  pending:    u32,
+  cq_head:    *const AtomicU32,
+  cq_tail:    *const AtomicU32,
+  cq_mask:    u32,
+  cq_entries: u32,
+  cqes:       *const Cqe,
+  // NB: The mappings are only kept for unmapping on drop, after `fd` is
+  // closed; `cq_map` is `None` with `IORING_FEAT_SINGLE_MMAP`.
+  _sq_map:    Mmap,
+  _cq_map:    Option<Mmap>,
+  _sqe_map:   Mmap,
+}
+
+unsafe impl Send for IoUring {}
+
+impl Drop for IoUring {
+  fn drop(&mut self) {
+    unsafe { libc::close(self.fd); }
+  }
+}
+
+impl IoUring {
+  /// Creates an `io_uring` with (at least) `entries` submission queue
+  /// entries, and twice as many completion queue entries.
+  ///
+  /// ## Notes
+  ///
+  /// * `io_uring_setup()` is the underlying syscall.
+  pub fn new(entries: u32) -> Result<IoUring, Error> {
+    let mut p = Params::default();
+    let res = unsafe { libc::syscall(libc::SYS_io_uring_setup, entries, &mut p as *mut Params) };
+    if res < 0 {
+      return Err(Error::last_os_error());
+    }
+    let fd = res as RawFd;
+    match IoUring::map(fd, &p) {
+      Ok(ring) => Ok(ring),
+      Err(e) => {
+        unsafe { libc::close(fd); }
+        Err(e)
+      }
+    }
+  }
+
+  fn map(fd: RawFd, p: &Params) -> Result<IoUring, Error> {
+    let mut sq_len = p.sq_off.array as usize + p.sq_entries as usize * size_of::<u32>();
+    let mut cq_len = p.cq_off.cqes as usize + p.cq_entries as usize * size_of::<Cqe>();
+    let single = (p.features & IORING_FEAT_SINGLE_MMAP) != 0;
+    if single {
+      sq_len = sq_len.max(cq_len);
+      cq_len = sq_len;
+    }
+    let sq_map = Mmap::map(fd, sq_len, IORING_OFF_SQ_RING)?;
+    let cq_map = if single { None } else { Some(Mmap::map(fd, cq_len, IORING_OFF_CQ_RING)?) };
+    let sqe_map = Mmap::map(fd, p.sq_entries as usize * size_of::<Sqe>(), IORING_OFF_SQES)?;
+    unsafe {
+      let cq = cq_map.as_ref().unwrap_or(&sq_map);
+      Ok(IoUring{
+        fd,
+        features:   p.features,
+        sq_head:    sq_map.at(p.sq_off.head),
+        sq_tail:    sq_map.at(p.sq_off.tail),
+        sq_mask:    *sq_map.at::<u32>(p.sq_off.ring_mask),
+        sq_entries: *sq_map.at::<u32>(p.sq_off.ring_entries),
+        sq_array:   sq_map.at(p.sq_off.array),
+        sqes:       sqe_map.ptr as *mut Sqe,
+        pending:    0,
+        cq_head:    cq.at(p.cq_off.head),
+        cq_tail:    cq.at(p.cq_off.tail),
+        cq_mask:    *cq.at::<u32>(p.cq_off.ring_mask),
+        cq_entries: *cq.at::<u32>(p.cq_off.ring_entries),
+        cqes:       cq.at(p.cq_off.cqes),
+        _sq_map:    sq_map,
+        _cq_map:    cq_map,
+        _sqe_map:   sqe_map,
+      })
+    }
+  }
+
+  /// The `IORING_FEAT_*` flags reported by the kernel.
+  pub fn features(&self) -> u32 {
+    self.features
+  }
+
+  pub fn sq_capacity(&self) -> usize {
+    self.sq_entries as usize
+  }
+
+  pub fn cq_capacity(&self) -> usize {
+    self.cq_entries as usize
+  }
+
+  /// The number of pushed entries not yet submitted.
+  pub fn sq_pending(&self) -> usize {
+    self.pending as usize
+  }
+
+  /// Queries the opcodes supported by the kernel (since Linux 5.6).
+  ///
+  /// ## Notes
+  ///
+  /// * `io_uring_register(IORING_REGISTER_PROBE)` is the underlying syscall.
+  pub fn probe(&self) -> Result<Probe, Error> {
+    let mut raw: Box<RawProbe> = Box::new(unsafe { zeroed() });
+    let res = unsafe { libc::syscall(
+        libc::SYS_io_uring_register, self.fd, IORING_REGISTER_PROBE,
+        &mut *raw as *mut RawProbe, raw.ops.len() as libc::c_uint,
+    ) };
+    if res < 0 {
+      return Err(Error::last_os_error());
+    }
+    let n = raw.ops_len as usize;
+    Ok(Probe{
+      last_op:  raw.last_op,
+      flags:    raw.ops[ .. n].iter().map(|op| op.flags).collect(),
+    })
+  }
+
+  /// Pushes an entry onto the submission queue, to be submitted by the next
+  /// `submit` or `submit_and_wait`; fails with `WouldBlock` if the queue is
+  /// full.
+  ///
+  /// ## Safety
+  ///
+  /// All memory referenced by `sqe` must stay valid (and, for buffers read
+  /// into, unaliased) until its completion is reaped.
+  pub unsafe fn push(&mut self, sqe: &Sqe) -> Result<(), Error> {
+    let head = (*self.sq_head).load(Ordering::Acquire);
+    let tail = (*self.sq_tail).load(Ordering::Relaxed);
+    if tail.wrapping_sub(head) >= self.sq_entries {
+      return Err(Error::new(ErrorKind::WouldBlock, "io_uring submission queue is full"));
+    }
+    let idx = tail & self.sq_mask;
+    self.sqes.add(idx as usize).write(*sqe);
+    self.sq_array.add(idx as usize).write(idx);
+    (*self.sq_tail).store(tail.wrapping_add(1), Ordering::Release);
+    self.pending += 1;
+    Ok(())
+  }
+
+  fn enter(&mut self, min_complete: u32, flags: u32) -> Result<usize, Error> {
+    let res = unsafe { libc::syscall(
+        libc::SYS_io_uring_enter, self.fd, self.pending, min_complete, flags,
+        null_mut::<libc::sigset_t>(), 0 as libc::size_t,
+    ) };
+    if res < 0 {
+      return Err(Error::last_os_error());
+    }
+    let n = res as u32;
+    self.pending -= n.min(self.pending);
+    Ok(n as usize)
+  }
+
+  /// Submits the pushed entries without waiting; returns the number
+  /// submitted.
+  ///
+  /// ## Notes
+  ///
+  /// * `io_uring_enter()` is the underlying syscall.
+  pub fn submit(&mut self) -> Result<usize, Error> {
+    if self.pending == 0 {
+      return Ok(0);
+    }
+    self.enter(0, 0)
+  }
+
+  /// Submits the pushed entries, and waits until at least `want`
+  /// completions are available; returns the number submitted.
+  pub fn submit_and_wait(&mut self, want: usize) -> Result<usize, Error> {
+    self.enter(want as u32, IORING_ENTER_GETEVENTS)
+  }
+
+  /// The number of completions available to reap.
+  pub fn cq_len(&self) -> usize {
+    let head = unsafe { (*self.cq_head).load(Ordering::Relaxed) };
+    let tail = unsafe { (*self.cq_tail).load(Ordering::Acquire) };
+    tail.wrapping_sub(head) as usize
+  }
+
+  /// Reaps the next completion, if any.
+  pub fn pop_completion(&mut self) -> Option<Cqe> {
+    unsafe {
+      let head = (*self.cq_head).load(Ordering::Relaxed);
+      let tail = (*self.cq_tail).load(Ordering::Acquire);
+      if head == tail {
+        return None;
+      }
+      let cqe = self.cqes.add((head & self.cq_mask) as usize).read();
+      (*self.cq_head).store(head.wrapping_add(1), Ordering::Release);
+      Some(cqe)
+    }
+  }
+
+  /// Reaps all available completions.
+  pub fn completions(&mut self) -> Completions<'_> {
+    Completions{ring: self}
+  }
+}
+
+impl AsRawFd for IoUring {
+  fn as_raw_fd(&self) -> RawFd {
+    self.fd
+  }
+}
+
+/// An iterator reaping the available completions of an `IoUring`.
+pub struct Completions<'a> {
+  ring: &'a mut IoUring,
+}
+
+impl<'a> Iterator for Completions<'a> {
+  type Item = Cqe;
+
+  fn next(&mut self) -> Option<Cqe> {
+    self.ring.pop_completion()
+  }
+}
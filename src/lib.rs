@@ -1,21 +1,119 @@
 extern crate libc;
 
 use std::convert::{TryInto};
-use std::io::{Error};
+use std::ffi::{CString};
+use std::fmt;
+use std::io::{Error, ErrorKind};
 use std::mem::{MaybeUninit, zeroed};
 use std::os::unix::io::{AsRawFd, RawFd};
-use std::time::{Duration};
+use std::ptr::{null, null_mut};
+use std::time::{Duration, Instant};
 
+#[cfg(target_os = "linux")]
+pub mod caps;
 #[cfg(target_os = "linux")]
 pub mod epoll;
+#[cfg(target_os = "linux")]
+pub mod eventfd;
+#[cfg(target_os = "linux")]
+pub mod inotify;
+#[cfg(target_os = "linux")]
+pub mod io_uring;
+pub mod mode;
+#[cfg(target_os = "linux")]
+pub mod pidfd;
+pub mod poll;
+pub mod poller;
+#[cfg(target_os = "linux")]
+pub mod pool;
+#[cfg(target_os = "linux")]
+pub mod reactor;
+#[cfg(target_os = "linux")]
+pub mod rt;
+pub mod signal;
+#[cfg(target_os = "linux")]
+pub mod signalfd;
+#[cfg(target_os = "linux")]
+pub mod spawn;
+#[cfg(target_os = "linux")]
+pub mod timerfd;
+pub mod user;
+
+use crate::mode::{Mode};
+use crate::signal::{SigSet};
 
+/// How `set_gid_with` treats the supplementary group list.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SupplementaryGroups {
+  /// Replace the supplementary group list with just the new GID.
+  Reset,
+  /// Leave the supplementary group list untouched.
+  Keep,
+}
+
+/// Sets the GID, replacing the supplementary group list with just `gid`.
+///
+/// This is equivalent to `set_gid_with(gid, SupplementaryGroups::Reset)`.
 pub fn set_gid(gid: u32) -> Result<(), Error> {
+  set_gid_with(gid, SupplementaryGroups::Reset)
+}
+
+/// Sets the GID, treating the supplementary group list according to `groups`.
+pub fn set_gid_with(gid: u32, groups: SupplementaryGroups) -> Result<(), Error> {
   unsafe {
-    let res = libc::setgroups(1, &gid);
+    if let SupplementaryGroups::Reset = groups {
+      let res = libc::setgroups(1, &gid);
+      if res != 0 {
+        return Err(Error::last_os_error());
+      }
+    }
+    let res = libc::setgid(gid);
     if res != 0 {
       return Err(Error::last_os_error());
     }
-    let res = libc::setgid(gid);
+  }
+  Ok(())
+}
+
+/// Returns the supplementary group list of the calling process.
+pub fn get_groups() -> Result<Vec<u32>, Error> {
+  loop {
+    let n = unsafe { libc::getgroups(0, null_mut()) };
+    if n < 0 {
+      return Err(Error::last_os_error());
+    }
+    let mut groups: Vec<libc::gid_t> = vec![0; n as usize];
+    let res = unsafe { libc::getgroups(n, groups.as_mut_ptr()) };
+    if res < 0 {
+      let e = Error::last_os_error();
+      // NB: The group list may have grown between the two calls.
+      if e.raw_os_error() == Some(libc::EINVAL) {
+        continue;
+      }
+      return Err(e);
+    }
+    groups.truncate(res as usize);
+    return Ok(groups);
+  }
+}
+
+/// Replaces the supplementary group list of the calling process.
+pub fn set_groups(groups: &[u32]) -> Result<(), Error> {
+  unsafe {
+    let res = libc::setgroups(groups.len() as _, groups.as_ptr());
+    if res != 0 {
+      return Err(Error::last_os_error());
+    }
+  }
+  Ok(())
+}
+
+/// Sets the supplementary group list to the groups that `user` is a member
+/// of, plus `gid`, using `initgroups`.
+pub fn init_groups(user: &str, gid: u32) -> Result<(), Error> {
+  let user = CString::new(user).map_err(|_| Error::new(ErrorKind::InvalidInput, "user contains a nul byte"))?;
+  unsafe {
+    let res = libc::initgroups(user.as_ptr(), gid as _);
     if res != 0 {
       return Err(Error::last_os_error());
     }
@@ -33,6 +131,117 @@ pub fn set_uid(uid: u32) -> Result<(), Error> {
   Ok(())
 }
 
+/// The step of `drop_privileges` that failed.
+#[cfg(target_os = "linux")]
+#[derive(Debug)]
+pub enum DropPrivilegesError {
+  SetGroups(Error),
+  SetResGid(Error),
+  SetResUid(Error),
+  GetResGid(Error),
+  GetResUid(Error),
+  /// `getresgid` did not report the requested GID for all of the real,
+  /// effective, and saved-set IDs.
+  GidMismatch{real: u32, effective: u32, saved: u32},
+  /// `getresuid` did not report the requested UID for all of the real,
+  /// effective, and saved-set IDs.
+  UidMismatch{real: u32, effective: u32, saved: u32},
+  /// A subsequent `setuid(0)` succeeded, i.e. root could be regained.
+  RegainedRoot,
+}
+
+#[cfg(target_os = "linux")]
+impl fmt::Display for DropPrivilegesError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match self {
+      DropPrivilegesError::SetGroups(e) => write!(f, "drop_privileges: setgroups failed: {}", e),
+      DropPrivilegesError::SetResGid(e) => write!(f, "drop_privileges: setresgid failed: {}", e),
+      DropPrivilegesError::SetResUid(e) => write!(f, "drop_privileges: setresuid failed: {}", e),
+      DropPrivilegesError::GetResGid(e) => write!(f, "drop_privileges: getresgid failed: {}", e),
+      DropPrivilegesError::GetResUid(e) => write!(f, "drop_privileges: getresuid failed: {}", e),
+      DropPrivilegesError::GidMismatch{real, effective, saved} => {
+        write!(f, "drop_privileges: gid not dropped (real={} effective={} saved={})", real, effective, saved)
+      }
+      DropPrivilegesError::UidMismatch{real, effective, saved} => {
+        write!(f, "drop_privileges: uid not dropped (real={} effective={} saved={})", real, effective, saved)
+      }
+      DropPrivilegesError::RegainedRoot => write!(f, "drop_privileges: setuid(0) unexpectedly succeeded"),
+    }
+  }
+}
+
+#[cfg(target_os = "linux")]
+impl std::error::Error for DropPrivilegesError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    match self {
+      DropPrivilegesError::SetGroups(e) |
+      DropPrivilegesError::SetResGid(e) |
+      DropPrivilegesError::SetResUid(e) |
+      DropPrivilegesError::GetResGid(e) |
+      DropPrivilegesError::GetResUid(e) => Some(e),
+      _ => None,
+    }
+  }
+}
+
+#[cfg(target_os = "linux")]
+impl From<DropPrivilegesError> for Error {
+  fn from(e: DropPrivilegesError) -> Error {
+    Error::new(ErrorKind::PermissionDenied, e)
+  }
+}
+
+/// Permanently drops privileges to `uid` and `gid`, replacing the
+/// supplementary group list with `supplementary`.
+///
+/// The groups are set first (while still privileged), then the real,
+/// effective, and saved-set GIDs, and finally the real, effective, and
+/// saved-set UIDs. The result is then checked with `getresgid`/`getresuid`,
+/// and, if `uid` is nonzero, by verifying that `setuid(0)` fails.
+///
+/// On error the caller should assume the process is in an unknown state
+/// and exit.
+#[cfg(target_os = "linux")]
+pub fn drop_privileges(uid: u32, gid: u32, supplementary: &[u32]) -> Result<(), DropPrivilegesError> {
+  unsafe {
+    let res = libc::setgroups(supplementary.len() as _, supplementary.as_ptr());
+    if res != 0 {
+      return Err(DropPrivilegesError::SetGroups(Error::last_os_error()));
+    }
+    let res = libc::setresgid(gid, gid, gid);
+    if res != 0 {
+      return Err(DropPrivilegesError::SetResGid(Error::last_os_error()));
+    }
+    let res = libc::setresuid(uid, uid, uid);
+    if res != 0 {
+      return Err(DropPrivilegesError::SetResUid(Error::last_os_error()));
+    }
+    let (mut real, mut effective, mut saved) = (0, 0, 0);
+    let res = libc::getresgid(&mut real, &mut effective, &mut saved);
+    if res != 0 {
+      return Err(DropPrivilegesError::GetResGid(Error::last_os_error()));
+    }
+    if real != gid || effective != gid || saved != gid {
+      return Err(DropPrivilegesError::GidMismatch{real, effective, saved});
+    }
+    let (mut real, mut effective, mut saved) = (0, 0, 0);
+    let res = libc::getresuid(&mut real, &mut effective, &mut saved);
+    if res != 0 {
+      return Err(DropPrivilegesError::GetResUid(Error::last_os_error()));
+    }
+    if real != uid || effective != uid || saved != uid {
+      return Err(DropPrivilegesError::UidMismatch{real, effective, saved});
+    }
+    if uid != 0 {
+      let res = libc::setuid(0);
+      if res == 0 {
+        return Err(DropPrivilegesError::RegainedRoot);
+      }
+    }
+  }
+  Ok(())
+}
+
 pub fn umask(mode: u32) -> Result<u32, Error> {
   unsafe {
     let prev = libc::umask(mode);
@@ -40,6 +249,41 @@ pub fn umask(mode: u32) -> Result<u32, Error> {
   }
 }
 
+/// Sets the file mode creation mask, returning the previous mask.
+pub fn set_umask(mask: Mode) -> Mode {
+  let prev = unsafe { libc::umask(mask.bits() as _) };
+  Mode::from_bits(prev as u32)
+}
+
+/// Sets the file mode creation mask for as long as the guard is alive;
+/// the previous mask is restored on drop.
+///
+/// Note that the mask is process-wide, so the guard should not be held
+/// across code running concurrently in other threads that creates files.
+pub struct UmaskGuard {
+  prev: Mode,
+}
+
+impl Drop for UmaskGuard {
+  fn drop(&mut self) {
+    set_umask(self.prev);
+  }
+}
+
+impl UmaskGuard {
+  pub fn new(mask: Mode) -> UmaskGuard {
+    let prev = set_umask(mask);
+    UmaskGuard{prev}
+  }
+
+  /// The mask that will be restored on drop.
+  pub fn prev(&self) -> Mode {
+    self.prev
+  }
+}
+
+const FD_SETSIZE: RawFd = libc::FD_SETSIZE as RawFd;
+
 #[derive(Clone, Copy)]
 pub struct FdSet {
   raw:  libc::fd_set,
@@ -60,27 +304,236 @@ impl FdSet {
     }
   }
 
-  pub fn insert<F: AsRawFd>(&mut self, fd: &F) {
-    let fd = fd.as_raw_fd();
+  fn check_fd(fd: RawFd) -> Result<(), Error> {
+    if !(0 .. FD_SETSIZE).contains(&fd) {
+      return Err(Error::new(ErrorKind::InvalidInput, format!("fd {} out of range for FdSet", fd)));
+    }
+    Ok(())
+  }
+
+  /// Adds `fd` to the set.
+  ///
+  /// Fails if `fd` is negative or not less than `FD_SETSIZE`.
+  pub fn insert<F: AsRawFd>(&mut self, fd: &F) -> Result<(), Error> {
+    self.insert_raw(fd.as_raw_fd())
+  }
+
+  pub fn insert_raw(&mut self, fd: RawFd) -> Result<(), Error> {
+    FdSet::check_fd(fd)?;
     unsafe {
       libc::FD_SET(fd, &mut self.raw);
     }
+    Ok(())
+  }
+
+  /// Removes `fd` from the set; out of range descriptors are ignored.
+  pub fn remove<F: AsRawFd>(&mut self, fd: &F) {
+    self.remove_raw(fd.as_raw_fd())
+  }
+
+  pub fn remove_raw(&mut self, fd: RawFd) {
+    if FdSet::check_fd(fd).is_err() {
+      return;
+    }
+    unsafe {
+      libc::FD_CLR(fd, &mut self.raw);
+    }
+  }
+
+  pub fn contains<F: AsRawFd>(&self, fd: &F) -> bool {
+    self.contains_raw(fd.as_raw_fd())
+  }
+
+  pub fn contains_raw(&self, fd: RawFd) -> bool {
+    if FdSet::check_fd(fd).is_err() {
+      return false;
+    }
+    unsafe { libc::FD_ISSET(fd, &self.raw) }
+  }
+
+  pub fn clear(&mut self) {
+    unsafe {
+      libc::FD_ZERO(&mut self.raw);
+    }
+  }
+
+  pub fn is_empty(&self) -> bool {
+    self.iter(FD_SETSIZE).next().is_none()
+  }
+
+  /// Returns an iterator over the descriptors in the set that are less
+  /// than `end_fd`.
+  pub fn iter(&self, end_fd: RawFd) -> FdSetIter<'_> {
+    let end_fd = end_fd.clamp(0, FD_SETSIZE);
+    FdSetIter{set: self, fd: 0, end_fd}
+  }
+}
+
+pub struct FdSetIter<'a> {
+  set:    &'a FdSet,
+  fd:     RawFd,
+  end_fd: RawFd,
+}
+
+impl<'a> Iterator for FdSetIter<'a> {
+  type Item = RawFd;
+
+  fn next(&mut self) -> Option<RawFd> {
+    while self.fd < self.end_fd {
+      let fd = self.fd;
+      self.fd += 1;
+      if self.set.contains_raw(fd) {
+        return Some(fd);
+      }
+    }
+    None
   }
 }
 
-pub fn select(end_fd: RawFd, read: &mut FdSet, write: &mut FdSet, except: &mut FdSet, timeout: Duration) -> Result<Option<()>, Error> {
-  unsafe {
-    let mut tval: libc::timeval = zeroed();
-    tval.tv_sec = timeout.as_secs().try_into().unwrap();
-    tval.tv_usec = timeout.subsec_micros().try_into().unwrap();
-    let res = libc::select(end_fd, &mut read.raw, &mut write.raw, &mut except.raw, &mut tval);
-    if res < 0 {
-      return Err(Error::last_os_error());
+/// Converts a timeout to milliseconds for `poll`, rounding up so that a
+/// short nonzero timeout does not turn into a busy loop.
+pub(crate) fn timeout_millis(timeout: Option<Duration>) -> libc::c_int {
+  match timeout {
+    None => -1,
+    Some(t) => {
+      let ms = t.as_millis() + if t.subsec_nanos() % 1_000_000 != 0 { 1 } else { 0 };
+      ms.try_into().unwrap_or(libc::c_int::MAX)
     }
-    if res == 0 {
-      Ok(None)
-    } else {
-      Ok(Some(()))
+  }
+}
+
+/// Converts a duration to a `timespec`, saturating the seconds at
+/// `time_t::MAX`.
+pub(crate) fn to_timespec(d: Duration) -> libc::timespec {
+  let mut ts: libc::timespec = unsafe { zeroed() };
+  ts.tv_sec = d.as_secs().try_into().unwrap_or(libc::time_t::MAX);
+  ts.tv_nsec = d.subsec_nanos() as _;
+  ts
+}
+
+/// Checks that `end_fd` is within `0 ..= FD_SETSIZE`, since the kernel
+/// accesses `end_fd` bits of each set.
+fn check_end_fd(end_fd: RawFd) -> Result<(), Error> {
+  if !(0 ..= FD_SETSIZE).contains(&end_fd) {
+    return Err(Error::new(ErrorKind::InvalidInput, format!("end_fd {} is out of range for FdSet", end_fd)));
+  }
+  Ok(())
+}
+
+/// Waits until one of the descriptors in the given sets (each below
+/// `end_fd`) is ready, or `timeout` elapses. A `timeout` of `None` blocks
+/// indefinitely, and sets that are `None` are not watched.
+///
+/// Returns the number of ready descriptors (0 on timeout); on return, the
+/// sets only contain the ready descriptors.
+///
+/// If `restart` is true, the call is retried on `EINTR` with the remaining
+/// time, and with the sets as they were originally passed in.
+///
+/// Fails with `InvalidInput` if `end_fd` exceeds `FD_SETSIZE`.
+pub fn select_ready(end_fd: RawFd, mut read: Option<&mut FdSet>, mut write: Option<&mut FdSet>, mut except: Option<&mut FdSet>, timeout: Option<Duration>, restart: bool) -> Result<usize, Error> {
+  check_end_fd(end_fd)?;
+  let deadline = timeout.and_then(|t| Instant::now().checked_add(t));
+  let saved = (read.as_deref().copied(), write.as_deref().copied(), except.as_deref().copied());
+  let mut remaining = timeout;
+  loop {
+    let mut tval: libc::timeval = unsafe { zeroed() };
+    let tval_ptr = match remaining {
+      None => null_mut(),
+      Some(t) => {
+        tval.tv_sec = t.as_secs().try_into().unwrap_or(libc::time_t::MAX);
+        tval.tv_usec = t.subsec_micros() as _;
+        &mut tval as *mut _
+      }
+    };
+    let res = unsafe {
+      libc::select(
+          end_fd,
+          read.as_deref_mut().map_or(null_mut(), |s| &mut s.raw),
+          write.as_deref_mut().map_or(null_mut(), |s| &mut s.raw),
+          except.as_deref_mut().map_or(null_mut(), |s| &mut s.raw),
+          tval_ptr,
+      )
+    };
+    if res >= 0 {
+      return Ok(res as usize);
+    }
+    let e = Error::last_os_error();
+    if !restart || e.kind() != ErrorKind::Interrupted {
+      return Err(e);
+    }
+    if let (Some(s), Some(saved)) = (read.as_deref_mut(), saved.0) { *s = saved; }
+    if let (Some(s), Some(saved)) = (write.as_deref_mut(), saved.1) { *s = saved; }
+    if let (Some(s), Some(saved)) = (except.as_deref_mut(), saved.2) { *s = saved; }
+    if let Some(deadline) = deadline {
+      remaining = Some(deadline.saturating_duration_since(Instant::now()));
     }
   }
 }
+
+/// Like `select_ready`, but with a nanosecond-precision `timeout`, and with
+/// the signal mask atomically replaced by `sigmask` (if given) for the
+/// duration of the call.
+///
+/// `EINTR` is never retried, since being interrupted by one of the
+/// unblocked signals is usually the point. Fails with `InvalidInput` if
+/// `end_fd` exceeds `FD_SETSIZE`.
+pub fn pselect(end_fd: RawFd, read: Option<&mut FdSet>, write: Option<&mut FdSet>, except: Option<&mut FdSet>, timeout: Option<Duration>, sigmask: Option<&SigSet>) -> Result<usize, Error> {
+  check_end_fd(end_fd)?;
+  let tspec = timeout.map(to_timespec);
+  let tspec_ptr = tspec.as_ref().map_or(null(), |t| t as *const _);
+  let res = unsafe {
+    libc::pselect(
+        end_fd,
+        read.map_or(null_mut(), |s| &mut s.raw),
+        write.map_or(null_mut(), |s| &mut s.raw),
+        except.map_or(null_mut(), |s| &mut s.raw),
+        tspec_ptr,
+        sigmask.map_or(null(), |s| s.as_raw()),
+    )
+  };
+  if res < 0 {
+    return Err(Error::last_os_error());
+  }
+  Ok(res as usize)
+}
+
+/// Waits on all three sets with a finite `timeout`, returning `None` on
+/// timeout.
+///
+/// See `select_ready` for the more general version; likewise fails with
+/// `InvalidInput` if `end_fd` exceeds `FD_SETSIZE`.
+pub fn select(end_fd: RawFd, read: &mut FdSet, write: &mut FdSet, except: &mut FdSet, timeout: Duration) -> Result<Option<()>, Error> {
+  let res = select_ready(end_fd, Some(read), Some(write), Some(except), Some(timeout), false)?;
+  if res == 0 {
+    Ok(None)
+  } else {
+    Ok(Some(()))
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  #[test]
+  fn select_rejects_end_fd_beyond_fd_setsize() {
+    let mut read = FdSet::new();
+    let e = select_ready(FD_SETSIZE + 1, Some(&mut read), None, None, Some(Duration::from_secs(0)), false).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    let e = select_ready(-1, Some(&mut read), None, None, Some(Duration::from_secs(0)), false).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    let (mut write, mut except) = (FdSet::new(), FdSet::new());
+    let e = select(2048, &mut read, &mut write, &mut except, Duration::from_secs(0)).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    assert_eq!(select_ready(FD_SETSIZE, Some(&mut read), None, None, Some(Duration::from_secs(0)), false).unwrap(), 0);
+  }
+
+  #[test]
+  fn pselect_rejects_end_fd_beyond_fd_setsize() {
+    let mut read = FdSet::new();
+    let e = pselect(2048, Some(&mut read), None, None, Some(Duration::from_secs(0)), None).unwrap_err();
+    assert_eq!(e.kind(), ErrorKind::InvalidInput);
+    assert_eq!(pselect(FD_SETSIZE, Some(&mut read), None, None, Some(Duration::from_secs(0)), None).unwrap(), 0);
+  }
+}
@@ -0,0 +1,142 @@
+// NB: `mode_t` is not `u32` on every platform.
+#![allow(clippy::unnecessary_cast)]
+
+use std::fmt;
+use std::ops::{BitAnd, BitOr, Not};
+
+/// File mode permission bits, as used by `umask`, `open`, `chmod`, etc.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[repr(transparent)]
+pub struct Mode {
+  pub bits: u32,
+}
+
+impl Mode {
+  #[inline]
+  pub fn empty() -> Mode {
+    Mode{bits: 0}
+  }
+
+  /// Returns the mode with `bits` truncated to the permission bits
+  /// (`0o7777`).
+  #[inline]
+  pub fn from_bits(bits: u32) -> Mode {
+    Mode{bits: bits & 0o7777}
+  }
+
+  #[inline]
+  pub fn bits(&self) -> u32 {
+    self.bits
+  }
+
+  #[inline]
+  pub fn contains(&self, other: Mode) -> bool {
+    (self.bits & other.bits) == other.bits
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.bits == 0
+  }
+}
+
+impl From<Mode> for u32 {
+  #[inline]
+  fn from(mode: Mode) -> u32 {
+    mode.bits
+  }
+}
+
+impl From<std::fs::Permissions> for Mode {
+  #[inline]
+  fn from(perm: std::fs::Permissions) -> Mode {
+    use std::os::unix::fs::{PermissionsExt};
+    Mode::from_bits(perm.mode())
+  }
+}
+
+impl From<Mode> for std::fs::Permissions {
+  #[inline]
+  fn from(mode: Mode) -> std::fs::Permissions {
+    use std::os::unix::fs::{PermissionsExt};
+    std::fs::Permissions::from_mode(mode.bits)
+  }
+}
+
+impl BitAnd for Mode {
+  type Output = Mode;
+
+  #[inline]
+  fn bitand(self, rhs: Mode) -> Mode {
+    Mode{bits: self.bits & rhs.bits}
+  }
+}
+
+impl BitOr for Mode {
+  type Output = Mode;
+
+  #[inline]
+  fn bitor(self, rhs: Mode) -> Mode {
+    Mode{bits: self.bits | rhs.bits}
+  }
+}
+
+impl Not for Mode {
+  type Output = Mode;
+
+  #[inline]
+  fn not(self) -> Mode {
+    Mode::from_bits(!self.bits)
+  }
+}
+
+impl fmt::Display for Mode {
+  /// Formats the mode in the style of `ls -l`, e.g. `rwxr-x---`.
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    let bit = |m: Mode, c: char| if self.contains(m) { c } else { '-' };
+    let exec = |x: Mode, s: Mode, lo: char, hi: char| {
+      match (self.contains(x), self.contains(s)) {
+        (true,  true)  => lo,
+        (false, true)  => hi,
+        (true,  false) => 'x',
+        (false, false) => '-',
+      }
+    };
+    let s: String = [
+      bit(S_IRUSR, 'r'), bit(S_IWUSR, 'w'), exec(S_IXUSR, S_ISUID, 's', 'S'),
+      bit(S_IRGRP, 'r'), bit(S_IWGRP, 'w'), exec(S_IXGRP, S_ISGID, 's', 'S'),
+      bit(S_IROTH, 'r'), bit(S_IWOTH, 'w'), exec(S_IXOTH, S_ISVTX, 't', 'T'),
+    ].iter().collect();
+    f.pad(&s)
+  }
+}
+
+impl fmt::Octal for Mode {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    fmt::Octal::fmt(&self.bits, f)
+  }
+}
+
+pub const S_IRUSR: Mode = Mode{bits: libc::S_IRUSR as u32};
+pub const S_IWUSR: Mode = Mode{bits: libc::S_IWUSR as u32};
+pub const S_IXUSR: Mode = Mode{bits: libc::S_IXUSR as u32};
+pub const S_IRWXU: Mode = Mode{bits: libc::S_IRWXU as u32};
+
+pub const S_IRGRP: Mode = Mode{bits: libc::S_IRGRP as u32};
+pub const S_IWGRP: Mode = Mode{bits: libc::S_IWGRP as u32};
+pub const S_IXGRP: Mode = Mode{bits: libc::S_IXGRP as u32};
+pub const S_IRWXG: Mode = Mode{bits: libc::S_IRWXG as u32};
+
+pub const S_IROTH: Mode = Mode{bits: libc::S_IROTH as u32};
+pub const S_IWOTH: Mode = Mode{bits: libc::S_IWOTH as u32};
+pub const S_IXOTH: Mode = Mode{bits: libc::S_IXOTH as u32};
+pub const S_IRWXO: Mode = Mode{bits: libc::S_IRWXO as u32};
+
+/// Set-user-ID on execution.
+pub const S_ISUID: Mode = Mode{bits: libc::S_ISUID as u32};
+
+/// Set-group-ID on execution.
+pub const S_ISGID: Mode = Mode{bits: libc::S_ISGID as u32};
+
+/// Sticky bit; on directories, restricts deletion to the owners of entries.
+pub const S_ISVTX: Mode = Mode{bits: libc::S_ISVTX as u32};
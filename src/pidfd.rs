@@ -0,0 +1,222 @@
+use std::fmt;
+use std::io::{Error};
+use std::mem::{zeroed};
+use std::ops::{BitOr};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::ptr::{null};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(transparent)]
+pub struct PidFdFlags {
+  pub bits: libc::c_uint,
+}
+
+impl PidFdFlags {
+  #[inline]
+  pub fn empty() -> PidFdFlags {
+    PidFdFlags{bits: 0}
+  }
+
+  #[inline]
+  pub fn bits(&self) -> libc::c_uint {
+    self.bits
+  }
+}
+
+impl BitOr for PidFdFlags {
+  type Output = PidFdFlags;
+
+  #[inline]
+  fn bitor(self, rhs: PidFdFlags) -> PidFdFlags {
+    PidFdFlags{bits: self.bits | rhs.bits}
+  }
+}
+
+/// Make `wait` on the new pidfd fail with `WouldBlock` if the process has
+/// not exited, instead of blocking (since Linux 5.10).
+pub const PIDFD_NONBLOCK: PidFdFlags = PidFdFlags{bits: libc::O_NONBLOCK as libc::c_uint};
+
+/// How a child process terminated.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum ExitStatus {
+  /// The process exited with the given status code.
+  Exited(libc::c_int),
+  /// The process was killed by the given signal.
+  Signaled{signal: libc::c_int, core_dumped: bool},
+}
+
+impl ExitStatus {
+  fn from_siginfo(info: &libc::siginfo_t) -> Option<ExitStatus> {
+    let status = unsafe { info.si_status() };
+    match info.si_code {
+      libc::CLD_EXITED => Some(ExitStatus::Exited(status)),
+      libc::CLD_KILLED => Some(ExitStatus::Signaled{signal: status, core_dumped: false}),
+      libc::CLD_DUMPED => Some(ExitStatus::Signaled{signal: status, core_dumped: true}),
+      _ => None,
+    }
+  }
+
+  /// Decodes a status returned by `waitpid`; returns `None` for stopped or
+  /// continued processes.
+  pub fn from_wait_status(status: libc::c_int) -> Option<ExitStatus> {
+    if libc::WIFEXITED(status) {
+      Some(ExitStatus::Exited(libc::WEXITSTATUS(status)))
+    } else if libc::WIFSIGNALED(status) {
+      Some(ExitStatus::Signaled{signal: libc::WTERMSIG(status), core_dumped: libc::WCOREDUMP(status)})
+    } else {
+      None
+    }
+  }
+
+  /// Whether the process exited with status 0.
+  pub fn success(&self) -> bool {
+    *self == ExitStatus::Exited(0)
+  }
+
+  /// The exit status code, if the process exited normally.
+  pub fn code(&self) -> Option<libc::c_int> {
+    match *self {
+      ExitStatus::Exited(code) => Some(code),
+      _ => None,
+    }
+  }
+
+  /// The signal that killed the process, if any.
+  pub fn signal(&self) -> Option<libc::c_int> {
+    match *self {
+      ExitStatus::Signaled{signal, ..} => Some(signal),
+      _ => None,
+    }
+  }
+}
+
+impl fmt::Display for ExitStatus {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    match *self {
+      ExitStatus::Exited(code) => write!(f, "exit status: {}", code),
+      ExitStatus::Signaled{signal, core_dumped: false} => write!(f, "signal: {}", signal),
+      ExitStatus::Signaled{signal, core_dumped: true} => write!(f, "signal: {} (core dumped)", signal),
+    }
+  }
+}
+
+/// A file descriptor referring to a process, which becomes readable (e.g.
+/// `EPOLLIN` in an `Epoll`) when the process exits.
+///
+/// Unlike a PID, a pidfd cannot be recycled to refer to a different process.
+#[derive(Debug)]
+pub struct PidFd {
+  fd: RawFd,
+}
+
+impl Drop for PidFd {
+  fn drop(&mut self) {
+    unsafe { libc::close(self.fd); }
+  }
+}
+
+impl PidFd {
+  /// Opens a pidfd for the process `pid`, which must be a thread-group
+  /// leader. `FD_CLOEXEC` is always set.
+  ///
+  /// ## Notes
+  ///
+  /// * `pidfd_open()` is the underlying syscall (since Linux 5.3).
+  pub fn open(pid: libc::pid_t, flags: PidFdFlags) -> Result<PidFd, Error> {
+    let res = unsafe { libc::syscall(libc::SYS_pidfd_open, pid, flags.bits()) };
+    if res < 0 {
+      return Err(Error::last_os_error());
+    }
+    Ok(PidFd{fd: res as RawFd})
+  }
+
+  /// Sends `signal` to the process.
+  ///
+  /// ## Notes
+  ///
+  /// * `pidfd_send_signal()` is the underlying syscall (since Linux 5.1).
+  pub fn send_signal(&self, signal: libc::c_int) -> Result<(), Error> {
+    let res = unsafe { libc::syscall(
+        libc::SYS_pidfd_send_signal, self.fd, signal,
+        null::<libc::siginfo_t>(), 0 as libc::c_uint,
+    ) };
+    if res < 0 {
+      return Err(Error::last_os_error());
+    }
+    Ok(())
+  }
+
+  /// Duplicates the file descriptor `target_fd` of the process into the
+  /// calling process; the new fd is `FD_CLOEXEC`, and owned by the caller.
+  ///
+  /// Requires `PTRACE_MODE_ATTACH_REALCREDS` permission over the process.
+  ///
+  /// ## Notes
+  ///
+  /// * `pidfd_getfd()` is the underlying syscall (since Linux 5.6).
+  pub fn get_fd(&self, target_fd: RawFd) -> Result<RawFd, Error> {
+    let res = unsafe { libc::syscall(libc::SYS_pidfd_getfd, self.fd, target_fd, 0 as libc::c_uint) };
+    if res < 0 {
+      return Err(Error::last_os_error());
+    }
+    Ok(res as RawFd)
+  }
+
+  fn waitid(&self, options: libc::c_int) -> Result<Option<ExitStatus>, Error> {
+    let mut info: libc::siginfo_t = unsafe { zeroed() };
+    loop {
+      let res = unsafe { libc::waitid(libc::P_PIDFD, self.fd as libc::id_t, &mut info, libc::WEXITED | options) };
+      if res < 0 {
+        let e = Error::last_os_error();
+        if e.raw_os_error() == Some(libc::EINTR) {
+          continue;
+        }
+        return Err(e);
+      }
+      break;
+    }
+    if unsafe { info.si_pid() } == 0 {
+      return Ok(None);
+    }
+    Ok(ExitStatus::from_siginfo(&info))
+  }
+
+  /// Waits for the process, which must be a child of the calling process,
+  /// to exit, and reaps it.
+  ///
+  /// ## Notes
+  ///
+  /// * `waitid(P_PIDFD)` is the underlying syscall (since Linux 5.4).
+  pub fn wait(&self) -> Result<ExitStatus, Error> {
+    match self.waitid(0)? {
+      Some(status) => Ok(status),
+      None => Err(Error::from_raw_os_error(libc::EAGAIN)),
+    }
+  }
+
+  /// Reaps the process, which must be a child of the calling process, if it
+  /// has exited; returns `None` if it is still running.
+  pub fn try_wait(&self) -> Result<Option<ExitStatus>, Error> {
+    self.waitid(libc::WNOHANG)
+  }
+}
+
+impl AsRawFd for PidFd {
+  fn as_raw_fd(&self) -> RawFd {
+    self.fd
+  }
+}
+
+impl IntoRawFd for PidFd {
+  fn into_raw_fd(self) -> RawFd {
+    let fd = self.fd;
+    std::mem::forget(self);
+    fd
+  }
+}
+
+impl FromRawFd for PidFd {
+  unsafe fn from_raw_fd(fd: RawFd) -> PidFd {
+    PidFd{fd}
+  }
+}
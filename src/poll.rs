@@ -0,0 +1,183 @@
+use std::io::{Error};
+use std::marker::{PhantomData};
+use std::mem::{zeroed};
+use std::ops::{BitAnd, BitOr};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::time::{Duration};
+
+use crate::{timeout_millis};
+#[cfg(target_os = "linux")]
+use crate::{to_timespec};
+#[cfg(target_os = "linux")]
+use crate::signal::{SigSet};
+
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+#[repr(transparent)]
+pub struct PollFlags {
+  pub bits: i16,
+}
+
+impl PollFlags {
+  #[inline]
+  pub fn empty() -> PollFlags {
+    PollFlags{bits: 0}
+  }
+
+  #[inline]
+  pub fn from_bits(bits: i16) -> PollFlags {
+    PollFlags{bits}
+  }
+
+  #[inline]
+  pub fn bits(&self) -> i16 {
+    self.bits
+  }
+
+  #[inline]
+  pub fn contains(&self, other: PollFlags) -> bool {
+    (self.bits & other.bits) == other.bits
+  }
+
+  #[inline]
+  pub fn intersects(&self, other: PollFlags) -> bool {
+    (self.bits & other.bits) != 0
+  }
+
+  #[inline]
+  pub fn is_empty(&self) -> bool {
+    self.bits == 0
+  }
+}
+
+impl BitAnd for PollFlags {
+  type Output = PollFlags;
+
+  #[inline]
+  fn bitand(self, rhs: PollFlags) -> PollFlags {
+    PollFlags{bits: self.bits & rhs.bits}
+  }
+}
+
+impl BitOr for PollFlags {
+  type Output = PollFlags;
+
+  #[inline]
+  fn bitor(self, rhs: PollFlags) -> PollFlags {
+    PollFlags{bits: self.bits | rhs.bits}
+  }
+}
+
+/// There is data to read.
+pub const POLLIN: PollFlags = PollFlags{bits: libc::POLLIN};
+
+/// There is some exceptional condition on the file descriptor, e.g. out-of-band
+/// data on a TCP socket.
+pub const POLLPRI: PollFlags = PollFlags{bits: libc::POLLPRI};
+
+/// Writing is now possible.
+pub const POLLOUT: PollFlags = PollFlags{bits: libc::POLLOUT};
+
+pub const POLLRDNORM: PollFlags = PollFlags{bits: libc::POLLRDNORM};
+pub const POLLRDBAND: PollFlags = PollFlags{bits: libc::POLLRDBAND};
+pub const POLLWRNORM: PollFlags = PollFlags{bits: libc::POLLWRNORM};
+pub const POLLWRBAND: PollFlags = PollFlags{bits: libc::POLLWRBAND};
+
+/// Stream socket peer closed connection, or shut down writing half of
+/// connection.
+#[cfg(target_os = "linux")]
+pub const POLLRDHUP: PollFlags = PollFlags{bits: libc::POLLRDHUP};
+
+/// Error condition; only returned in `revents`.
+pub const POLLERR: PollFlags = PollFlags{bits: libc::POLLERR};
+
+/// Hang up; only returned in `revents`.
+pub const POLLHUP: PollFlags = PollFlags{bits: libc::POLLHUP};
+
+/// Invalid request: the file descriptor is not open; only returned in
+/// `revents`.
+pub const POLLNVAL: PollFlags = PollFlags{bits: libc::POLLNVAL};
+
+/// 'libc::pollfd' equivalent, borrowing the polled file descriptor.
+///
+/// SAFETY: This must have the same layout as `libc::pollfd`.
+#[derive(Clone, Copy)]
+#[repr(transparent)]
+pub struct PollFd<'fd> {
+  raw:  libc::pollfd,
+  _mrk: PhantomData<&'fd ()>,
+}
+
+impl<'fd> PollFd<'fd> {
+  pub fn new<F: AsRawFd + ?Sized>(fd: &'fd F, events: PollFlags) -> PollFd<'fd> {
+    let mut raw: libc::pollfd = unsafe { zeroed() };
+    raw.fd = fd.as_raw_fd();
+    raw.events = events.bits();
+    PollFd{raw, _mrk: PhantomData}
+  }
+
+  #[inline]
+  pub fn fd(&self) -> RawFd {
+    self.raw.fd
+  }
+
+  #[inline]
+  pub fn events(&self) -> PollFlags {
+    PollFlags::from_bits(self.raw.events)
+  }
+
+  #[inline]
+  pub fn set_events(&mut self, events: PollFlags) {
+    self.raw.events = events.bits();
+  }
+
+  /// The events that occurred, as filled in by the last `poll`/`ppoll`.
+  #[inline]
+  pub fn revents(&self) -> PollFlags {
+    PollFlags::from_bits(self.raw.revents)
+  }
+}
+
+impl<'fd> std::fmt::Debug for PollFd<'fd> {
+  fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+    f.debug_struct("PollFd")
+      .field("fd", &self.fd())
+      .field("events", &self.events())
+      .field("revents", &self.revents())
+      .finish()
+  }
+}
+
+/// Waits until one of `fds` is ready, or `timeout` elapses. A `timeout` of
+/// `None` blocks indefinitely.
+///
+/// Returns the number of entries with nonempty `revents` (0 on timeout).
+pub fn poll(fds: &mut [PollFd], timeout: Option<Duration>) -> Result<usize, Error> {
+  let res = unsafe {
+    libc::poll(fds.as_mut_ptr() as *mut libc::pollfd, fds.len() as libc::nfds_t, timeout_millis(timeout))
+  };
+  if res < 0 {
+    return Err(Error::last_os_error());
+  }
+  Ok(res as usize)
+}
+
+/// Like `poll`, but with a nanosecond-precision `timeout`, and with the
+/// signal mask atomically replaced by `sigmask` (if given) for the duration
+/// of the call.
+#[cfg(target_os = "linux")]
+pub fn ppoll(fds: &mut [PollFd], timeout: Option<Duration>, sigmask: Option<&SigSet>) -> Result<usize, Error> {
+  let tspec = timeout.map(to_timespec);
+  let tspec_ptr = tspec.as_ref().map_or(std::ptr::null(), |t| t as *const _);
+  let res = unsafe {
+    libc::ppoll(
+        fds.as_mut_ptr() as *mut libc::pollfd,
+        fds.len() as libc::nfds_t,
+        tspec_ptr,
+        sigmask.map_or(std::ptr::null(), |s| s.as_raw()),
+    )
+  };
+  if res < 0 {
+    return Err(Error::last_os_error());
+  }
+  Ok(res as usize)
+}
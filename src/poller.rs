@@ -0,0 +1,325 @@
+//! A readiness-polling interface with interchangeable backends.
+//!
+//! All backends are level-triggered: a registered descriptor is reported by
+//! every `wait` for as long as it stays ready.
+
+use std::io::{Error, ErrorKind};
+use std::mem::{zeroed};
+use std::ops::{BitOr};
+use std::os::unix::io::{RawFd};
+use std::time::{Duration};
+
+#[cfg(target_os = "linux")]
+use crate::epoll::{self, Control, Epoll, Event};
+use crate::{FdSet, select_ready, timeout_millis};
+
+/// A user-chosen value identifying a registration, returned with its events.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct Token(pub u64);
+
+/// The readiness a registration is interested in.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub struct Interest {
+  readable: bool,
+  writable: bool,
+}
+
+impl Interest {
+  pub const READABLE: Interest = Interest{readable: true, writable: false};
+  pub const WRITABLE: Interest = Interest{readable: false, writable: true};
+
+  #[inline]
+  pub fn is_readable(&self) -> bool {
+    self.readable
+  }
+
+  #[inline]
+  pub fn is_writable(&self) -> bool {
+    self.writable
+  }
+}
+
+impl BitOr for Interest {
+  type Output = Interest;
+
+  #[inline]
+  fn bitor(self, rhs: Interest) -> Interest {
+    Interest{
+      readable: self.readable || rhs.readable,
+      writable: self.writable || rhs.writable,
+    }
+  }
+}
+
+/// A readiness event returned by `Poller::wait`.
+///
+/// `readable` means normal data (`POLLIN` or `EPOLLIN`) in every backend;
+/// urgent data (`POLLPRI` or `EPOLLPRI`) alone is not reported as
+/// readable, so that the backends agree on the same descriptor.
+///
+/// `hangup` and `error` may be reported regardless of the registered
+/// interest; the `select` backend never reports them.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct ReadyEvent {
+  pub token:    Token,
+  pub readable: bool,
+  pub writable: bool,
+  pub hangup:   bool,
+  pub error:    bool,
+}
+
+pub trait Poller {
+  /// Starts watching `fd`; fails if it is already registered.
+  fn register(&mut self, fd: RawFd, token: Token, interest: Interest) -> Result<(), Error>;
+
+  /// Changes the token and interest of an existing registration.
+  fn modify(&mut self, fd: RawFd, token: Token, interest: Interest) -> Result<(), Error>;
+
+  /// Stops watching `fd`.
+  fn deregister(&mut self, fd: RawFd) -> Result<(), Error>;
+
+  /// Clears `events`, then waits until at least one registration is ready,
+  /// or `timeout` elapses, and fills in `events`. A `timeout` of `None`
+  /// blocks indefinitely.
+  ///
+  /// Returns the number of events (0 on timeout).
+  fn wait(&mut self, events: &mut Vec<ReadyEvent>, timeout: Option<Duration>) -> Result<usize, Error>;
+}
+
+/// The available `Poller` backends.
+///
+/// The default is `Epoll` where available, and `Poll` otherwise.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub enum Backend {
+  #[cfg(target_os = "linux")]
+  #[default]
+  Epoll,
+  #[cfg_attr(not(target_os = "linux"), default)]
+  Poll,
+  Select,
+}
+
+impl Backend {
+  pub fn create(self) -> Result<Box<dyn Poller + Send>, Error> {
+    Ok(match self {
+      #[cfg(target_os = "linux")]
+      Backend::Epoll => Box::new(EpollPoller::new()?),
+      Backend::Poll => Box::new(PollPoller::new()),
+      Backend::Select => Box::new(SelectPoller::new()),
+    })
+  }
+}
+
+fn not_registered(fd: RawFd) -> Error {
+  Error::new(ErrorKind::NotFound, format!("fd {} is not registered", fd))
+}
+
+fn already_registered(fd: RawFd) -> Error {
+  Error::new(ErrorKind::AlreadyExists, format!("fd {} is already registered", fd))
+}
+
+#[cfg(target_os = "linux")]
+pub struct EpollPoller {
+  epoll:  Epoll,
+  buf:    Vec<Event>,
+}
+
+#[cfg(target_os = "linux")]
+impl EpollPoller {
+  pub fn new() -> Result<EpollPoller, Error> {
+    Ok(EpollPoller{
+      epoll:  Epoll::create(true)?,
+      buf:    vec![Event::default(); 256],
+    })
+  }
+
+  fn event(token: Token, interest: Interest) -> Event {
+    let mut events = epoll::EPOLLRDHUP;
+    if interest.readable {
+      events |= epoll::EPOLLIN;
+    }
+    if interest.writable {
+      events |= epoll::EPOLLOUT;
+    }
+    Event::new(events, token.0)
+  }
+}
+
+#[cfg(target_os = "linux")]
+impl Poller for EpollPoller {
+  fn register(&mut self, fd: RawFd, token: Token, interest: Interest) -> Result<(), Error> {
+    self.epoll.ctl(Control::EPOLL_CTL_ADD, fd, EpollPoller::event(token, interest))
+  }
+
+  fn modify(&mut self, fd: RawFd, token: Token, interest: Interest) -> Result<(), Error> {
+    self.epoll.ctl(Control::EPOLL_CTL_MOD, fd, EpollPoller::event(token, interest))
+  }
+
+  fn deregister(&mut self, fd: RawFd) -> Result<(), Error> {
+    self.epoll.ctl(Control::EPOLL_CTL_DEL, fd, Event::default())
+  }
+
+  fn wait(&mut self, events: &mut Vec<ReadyEvent>, timeout: Option<Duration>) -> Result<usize, Error> {
+    events.clear();
+    let n = self.epoll.wait(timeout_millis(timeout), &mut self.buf)?;
+    for ev in self.buf[ .. n].iter() {
+      let e = ev.events();
+      events.push(ReadyEvent{
+        token:    Token(ev.raw_data()),
+        readable: e.contains(epoll::EPOLLIN),
+        writable: e.is_writable(),
+        hangup:   e.is_hangup(),
+        error:    e.is_error(),
+      });
+    }
+    Ok(n)
+  }
+}
+
+#[derive(Default)]
+pub struct PollPoller {
+  fds:    Vec<libc::pollfd>,
+  tokens: Vec<Token>,
+}
+
+impl PollPoller {
+  pub fn new() -> PollPoller {
+    PollPoller::default()
+  }
+
+  fn find(&self, fd: RawFd) -> Option<usize> {
+    self.fds.iter().position(|p| p.fd == fd)
+  }
+
+  fn events(interest: Interest) -> libc::c_short {
+    let mut events = 0;
+    if interest.readable {
+      events |= libc::POLLIN;
+    }
+    if interest.writable {
+      events |= libc::POLLOUT;
+    }
+    events
+  }
+}
+
+impl Poller for PollPoller {
+  fn register(&mut self, fd: RawFd, token: Token, interest: Interest) -> Result<(), Error> {
+    if self.find(fd).is_some() {
+      return Err(already_registered(fd));
+    }
+    let mut p: libc::pollfd = unsafe { zeroed() };
+    p.fd = fd;
+    p.events = PollPoller::events(interest);
+    self.fds.push(p);
+    self.tokens.push(token);
+    Ok(())
+  }
+
+  fn modify(&mut self, fd: RawFd, token: Token, interest: Interest) -> Result<(), Error> {
+    let idx = self.find(fd).ok_or_else(|| not_registered(fd))?;
+    self.fds[idx].events = PollPoller::events(interest);
+    self.tokens[idx] = token;
+    Ok(())
+  }
+
+  fn deregister(&mut self, fd: RawFd) -> Result<(), Error> {
+    let idx = self.find(fd).ok_or_else(|| not_registered(fd))?;
+    self.fds.swap_remove(idx);
+    self.tokens.swap_remove(idx);
+    Ok(())
+  }
+
+  fn wait(&mut self, events: &mut Vec<ReadyEvent>, timeout: Option<Duration>) -> Result<usize, Error> {
+    events.clear();
+    let res = unsafe {
+      libc::poll(self.fds.as_mut_ptr(), self.fds.len() as libc::nfds_t, timeout_millis(timeout))
+    };
+    if res < 0 {
+      return Err(Error::last_os_error());
+    }
+    for (p, &token) in self.fds.iter().zip(self.tokens.iter()) {
+      let r = p.revents;
+      if r == 0 {
+        continue;
+      }
+      events.push(ReadyEvent{
+        token,
+        readable: (r & libc::POLLIN) != 0,
+        writable: (r & libc::POLLOUT) != 0,
+        hangup:   (r & libc::POLLHUP) != 0,
+        error:    (r & (libc::POLLERR | libc::POLLNVAL)) != 0,
+      });
+    }
+    Ok(events.len())
+  }
+}
+
+/// A `Poller` backed by `select`, so only descriptors below `FD_SETSIZE`
+/// can be registered.
+#[derive(Default)]
+pub struct SelectPoller {
+  entries:  Vec<(RawFd, Token, Interest)>,
+}
+
+impl SelectPoller {
+  pub fn new() -> SelectPoller {
+    SelectPoller::default()
+  }
+
+  fn find(&self, fd: RawFd) -> Option<usize> {
+    self.entries.iter().position(|e| e.0 == fd)
+  }
+}
+
+impl Poller for SelectPoller {
+  fn register(&mut self, fd: RawFd, token: Token, interest: Interest) -> Result<(), Error> {
+    if self.find(fd).is_some() {
+      return Err(already_registered(fd));
+    }
+    // NB: Check the bound up front, rather than on the next `wait`.
+    FdSet::new().insert_raw(fd)?;
+    self.entries.push((fd, token, interest));
+    Ok(())
+  }
+
+  fn modify(&mut self, fd: RawFd, token: Token, interest: Interest) -> Result<(), Error> {
+    let idx = self.find(fd).ok_or_else(|| not_registered(fd))?;
+    self.entries[idx] = (fd, token, interest);
+    Ok(())
+  }
+
+  fn deregister(&mut self, fd: RawFd) -> Result<(), Error> {
+    let idx = self.find(fd).ok_or_else(|| not_registered(fd))?;
+    self.entries.swap_remove(idx);
+    Ok(())
+  }
+
+  fn wait(&mut self, events: &mut Vec<ReadyEvent>, timeout: Option<Duration>) -> Result<usize, Error> {
+    events.clear();
+    let mut read = FdSet::new();
+    let mut write = FdSet::new();
+    let mut end_fd = 0;
+    for &(fd, _, interest) in self.entries.iter() {
+      if interest.readable {
+        read.insert_raw(fd)?;
+      }
+      if interest.writable {
+        write.insert_raw(fd)?;
+      }
+      end_fd = end_fd.max(fd + 1);
+    }
+    let n = select_ready(end_fd, Some(&mut read), Some(&mut write), None, timeout, false)?;
+    if n == 0 {
+      return Ok(0);
+    }
+    for &(fd, token, _) in self.entries.iter() {
+      let readable = read.contains_raw(fd);
+      let writable = write.contains_raw(fd);
+      if readable || writable {
+        events.push(ReadyEvent{token, readable, writable, hangup: false, error: false});
+      }
+    }
+    Ok(events.len())
+  }
+}
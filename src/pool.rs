@@ -0,0 +1,348 @@
+//! Multi-threaded worker pools over `Epoll`.
+//!
+//! `ExclusivePool` gives each worker thread its own `Epoll`, each attached
+//! to a shared listening socket with `EPOLLEXCLUSIVE`, so that a new
+//! connection wakes one (or a few) workers rather than all of them.
+//!
+//! `OneshotPool` shares a single `Epoll` between the workers, with every
+//! file descriptor registered with `EPOLLONESHOT`, so that each readiness
+//! event is handled by exactly one worker at a time; the registration is
+//! rearmed after the handler returns.
+
+use crate::epoll::{Epoll, Event, Events, EPOLLEXCLUSIVE, EPOLLIN, EPOLLONESHOT, WAKER_TOKEN};
+use crate::eventfd::{EFD_CLOEXEC, EFD_NONBLOCK, EventFd};
+
+use std::collections::{HashMap};
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::sync::{Arc, Mutex};
+use std::sync::atomic::{AtomicU32, Ordering};
+use std::thread::{self, JoinHandle};
+
+/// The event data under which the pool's shutdown eventfd is registered.
+pub const SHUTDOWN_DATA: u64 = WAKER_TOKEN;
+
+/// The event data under which `ExclusivePool` registers the listener.
+pub const LISTENER_DATA: u64 = u64::MAX - 1;
+
+fn wait_retry(epoll: &Epoll, buf: &mut [Event]) -> Result<usize, Error> {
+  loop {
+    match epoll.wait(-1, buf) {
+      Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+      res => return res,
+    }
+  }
+}
+
+fn join_all(shutdown: &EventFd, threads: &mut Vec<JoinHandle<()>>) {
+  // NB: The shutdown eventfd is level-triggered and never read, so it
+  // stays readable and wakes every worker.
+  let _ = shutdown.write(1);
+  for t in threads.drain(..) {
+    let _ = t.join();
+  }
+}
+
+/// A pool of worker threads, each with its own `Epoll` attached to a shared
+/// listener with `EPOLLEXCLUSIVE`.
+pub struct ExclusivePool {
+  shutdown: Arc<EventFd>,
+  threads:  Vec<JoinHandle<()>>,
+}
+
+impl Drop for ExclusivePool {
+  fn drop(&mut self) {
+    join_all(&self.shutdown, &mut self.threads);
+  }
+}
+
+impl ExclusivePool {
+  /// Spawns `nthreads` workers, each calling `handler` with its own `Epoll`
+  /// for every event it receives (other than shutdown).
+  ///
+  /// Events for the listener carry `LISTENER_DATA`; since `EPOLLEXCLUSIVE`
+  /// may still wake more than one worker, the listener should be
+  /// nonblocking, and the handler should accept until `WouldBlock`. The
+  /// handler may register further file descriptors (e.g. accepted
+  /// connections) in the worker's `Epoll`, under any other data.
+  pub fn spawn<L, H>(nthreads: usize, listener: Arc<L>, handler: H) -> Result<ExclusivePool, Error>
+  where L: AsRawFd + Send + Sync + 'static,
+        H: Fn(&Epoll, &L, Event) + Send + Sync + 'static,
+  {
+    let shutdown = Arc::new(EventFd::create(0, EFD_CLOEXEC | EFD_NONBLOCK)?);
+    let mut epolls = Vec::with_capacity(nthreads);
+    for _ in 0 .. nthreads {
+      let epoll = Epoll::create(true)?;
+      epoll.add(&*listener, EPOLLIN | EPOLLEXCLUSIVE, LISTENER_DATA)?;
+      epoll.add(&*shutdown, EPOLLIN, SHUTDOWN_DATA)?;
+      epolls.push(epoll);
+    }
+    let handler = Arc::new(handler);
+    let mut threads = Vec::with_capacity(nthreads);
+    for epoll in epolls {
+      let listener = listener.clone();
+      let handler = handler.clone();
+      threads.push(thread::spawn(move || {
+        let mut buf = [Event::default(); 64];
+        loop {
+          let n = match wait_retry(&epoll, &mut buf) {
+            Ok(n) => n,
+            Err(_) => return,
+          };
+          for &ev in buf[ .. n].iter() {
+            if ev.raw_data() == SHUTDOWN_DATA {
+              return;
+            }
+            handler(&epoll, &listener, ev);
+          }
+        }
+      }));
+    }
+    Ok(ExclusivePool{shutdown, threads})
+  }
+
+  /// Stops the workers (after their current handlers return) and joins
+  /// them.
+  pub fn shutdown(mut self) {
+    join_all(&self.shutdown, &mut self.threads);
+  }
+}
+
+struct Registration {
+  events:   Events,
+  // NB: Distinguishes this registration from earlier ones of the same fd
+  // number, which may have been closed and reused while a handler ran.
+  gen:      u32,
+  // NB: Whether a worker is running the handler; the fd is then disarmed,
+  // and only rearmed by that worker once the handler returns.
+  running:  bool,
+}
+
+struct OneshotInner {
+  epoll:    Epoll,
+  interest: Mutex<HashMap<RawFd, Registration>>,
+  next_gen: AtomicU32,
+  shutdown: EventFd,
+}
+
+/// Packs a registration into the epoll data word, with the fd in the low 32
+/// bits and the generation in the high 32 bits (as in `epoll::Token`).
+fn oneshot_data(fd: RawFd, gen: u32) -> u64 {
+  ((gen as u64) << 32) | (fd as u32 as u64)
+}
+
+/// A handle for registering file descriptors in a `OneshotPool`; passed to
+/// handlers, and may be cloned and sent to other threads.
+#[derive(Clone)]
+pub struct OneshotHandle {
+  inner: Arc<OneshotInner>,
+}
+
+impl OneshotHandle {
+  /// Registers `fd` with the given `events`; `EPOLLONESHOT` is added.
+  pub fn register<F: AsRawFd + ?Sized>(&self, fd: &F, events: Events) -> Result<(), Error> {
+    let fd = fd.as_raw_fd();
+    let mut interest = self.inner.interest.lock().unwrap();
+    if interest.contains_key(&fd) {
+      return Err(Error::new(ErrorKind::AlreadyExists, format!("fd {} is already registered", fd)));
+    }
+    let gen = self.inner.next_gen.fetch_add(1, Ordering::Relaxed);
+    self.inner.epoll.add(&fd, events | EPOLLONESHOT, oneshot_data(fd, gen))?;
+    interest.insert(fd, Registration{events, gen, running: false});
+    Ok(())
+  }
+
+  /// Changes the events of `fd`; if a handler is running for it, they take
+  /// effect when it is rearmed after the handler returns, otherwise
+  /// immediately.
+  pub fn modify<F: AsRawFd + ?Sized>(&self, fd: &F, events: Events) -> Result<(), Error> {
+    let fd = fd.as_raw_fd();
+    let mut interest = self.inner.interest.lock().unwrap();
+    let reg = match interest.get_mut(&fd) {
+      Some(reg) => reg,
+      None => return Err(Error::new(ErrorKind::NotFound, format!("fd {} is not registered", fd))),
+    };
+    reg.events = events;
+    if reg.running {
+      return Ok(());
+    }
+    self.inner.epoll.rearm(&fd, events, oneshot_data(fd, reg.gen))
+  }
+
+  /// Deregisters `fd`; when called from the handler for `fd`, it is not
+  /// rearmed.
+  pub fn deregister<F: AsRawFd + ?Sized>(&self, fd: &F) -> Result<(), Error> {
+    let fd = fd.as_raw_fd();
+    let mut interest = self.inner.interest.lock().unwrap();
+    if interest.remove(&fd).is_none() {
+      return Err(Error::new(ErrorKind::NotFound, format!("fd {} is not registered", fd)));
+    }
+    self.inner.epoll.delete(&fd)
+  }
+}
+
+/// A pool of worker threads sharing one `Epoll`, with `EPOLLONESHOT`
+/// registrations that are rearmed after each handler call.
+pub struct OneshotPool {
+  handle:   OneshotHandle,
+  threads:  Vec<JoinHandle<()>>,
+}
+
+impl Drop for OneshotPool {
+  fn drop(&mut self) {
+    join_all(&self.handle.inner.shutdown, &mut self.threads);
+  }
+}
+
+impl OneshotPool {
+  /// Spawns `nthreads` workers, each calling `handler` with the ready file
+  /// descriptor and its events.
+  ///
+  /// While the handler runs, no other worker receives events for the same
+  /// file descriptor. Afterwards, the file descriptor is rearmed with its
+  /// registered events, unless the handler deregistered it. A handler that
+  /// closes its file descriptor must deregister it first.
+  pub fn spawn<H>(nthreads: usize, handler: H) -> Result<OneshotPool, Error>
+  where H: Fn(&OneshotHandle, RawFd, Events) + Send + Sync + 'static,
+  {
+    let shutdown = EventFd::create(0, EFD_CLOEXEC | EFD_NONBLOCK)?;
+    let epoll = Epoll::create(true)?;
+    epoll.add(&shutdown, EPOLLIN, SHUTDOWN_DATA)?;
+    let handle = OneshotHandle{inner: Arc::new(OneshotInner{
+      epoll,
+      interest: Mutex::new(HashMap::new()),
+      next_gen: AtomicU32::new(0),
+      shutdown,
+    })};
+    let handler = Arc::new(handler);
+    let mut threads = Vec::with_capacity(nthreads);
+    for _ in 0 .. nthreads {
+      let handle = handle.clone();
+      let handler = handler.clone();
+      threads.push(thread::spawn(move || {
+        // NB: Take one event per wait, so that ready file descriptors are
+        // spread across idle workers instead of queueing behind one.
+        let mut buf = [Event::default(); 1];
+        loop {
+          let n = match wait_retry(&handle.inner.epoll, &mut buf) {
+            Ok(n) => n,
+            Err(_) => return,
+          };
+          if n == 0 {
+            continue;
+          }
+          let ev = buf[0];
+          if ev.raw_data() == SHUTDOWN_DATA {
+            return;
+          }
+          let fd = ev.raw_data() as u32 as RawFd;
+          let gen = (ev.raw_data() >> 32) as u32;
+          {
+            // NB: A `modify` between the wait returning and this point may
+            // have rearmed the fd, and another worker may already be
+            // running its handler; if so, leave the event to that worker.
+            let mut interest = handle.inner.interest.lock().unwrap();
+            match interest.get_mut(&fd) {
+              Some(reg) if reg.gen == gen && !reg.running => reg.running = true,
+              _ => continue,
+            }
+          }
+          handler(&handle, fd, ev.events());
+          // NB: If the handler deregistered the fd, the same fd number may
+          // have been registered again since; that registration belongs to
+          // whichever worker claims its events, so leave it alone.
+          let mut interest = handle.inner.interest.lock().unwrap();
+          match interest.get_mut(&fd) {
+            Some(reg) if reg.gen == gen => {
+              reg.running = false;
+              let _ = handle.inner.epoll.rearm(&fd, reg.events, oneshot_data(fd, gen));
+            }
+            _ => {}
+          }
+        }
+      }));
+    }
+    Ok(OneshotPool{handle, threads})
+  }
+
+  pub fn handle(&self) -> &OneshotHandle {
+    &self.handle
+  }
+
+  /// Stops the workers (after their current handlers return) and joins
+  /// them.
+  pub fn shutdown(mut self) {
+    join_all(&self.handle.inner.shutdown, &mut self.threads);
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::sync::atomic::{AtomicBool, AtomicUsize};
+  use std::time::{Duration};
+
+  #[test]
+  fn oneshot_modify_in_handler_keeps_one_worker_per_fd() {
+    // NB: The eventfd is never read, so it is always readable.
+    let efd = EventFd::create(1, EFD_CLOEXEC | EFD_NONBLOCK).unwrap();
+    let running = Arc::new(AtomicUsize::new(0));
+    let max_running = Arc::new(AtomicUsize::new(0));
+    let calls = Arc::new(AtomicUsize::new(0));
+    let (r, m, c) = (running.clone(), max_running.clone(), calls.clone());
+    let pool = OneshotPool::spawn(4, move |h: &OneshotHandle, fd: RawFd, _| {
+      let n = r.fetch_add(1, Ordering::SeqCst) + 1;
+      m.fetch_max(n, Ordering::SeqCst);
+      h.modify(&fd, EPOLLIN).unwrap();
+      thread::sleep(Duration::from_millis(2));
+      c.fetch_add(1, Ordering::SeqCst);
+      r.fetch_sub(1, Ordering::SeqCst);
+    }).unwrap();
+    pool.handle().register(&efd, EPOLLIN).unwrap();
+    thread::sleep(Duration::from_millis(100));
+    pool.handle().deregister(&efd).unwrap();
+    pool.shutdown();
+    assert!(calls.load(Ordering::SeqCst) > 1);
+    assert_eq!(max_running.load(Ordering::SeqCst), 1);
+  }
+
+  #[test]
+  fn oneshot_reregister_same_fd_in_handler_keeps_one_worker_per_fd() {
+    let efd = EventFd::create(1, EFD_CLOEXEC | EFD_NONBLOCK).unwrap();
+    let swapped = Arc::new(AtomicBool::new(false));
+    let running = Arc::new(AtomicUsize::new(0));
+    let max_running = Arc::new(AtomicUsize::new(0));
+    let calls = Arc::new(AtomicUsize::new(0));
+    let (s, r, m, c) = (swapped.clone(), running.clone(), max_running.clone(), calls.clone());
+    let pool = OneshotPool::spawn(4, move |h: &OneshotHandle, fd: RawFd, _| {
+      if !s.swap(true, Ordering::SeqCst) {
+        // NB: Replace the fd with a new (always readable) eventfd under the
+        // same number; `dup2` closes the old file, and unlike a `close`
+        // followed by a new eventfd, the number cannot be taken in between
+        // by another thread.
+        h.deregister(&fd).unwrap();
+        let new_efd = EventFd::create(1, EFD_CLOEXEC | EFD_NONBLOCK).unwrap();
+        assert_eq!(unsafe { libc::dup2(new_efd.as_raw_fd(), fd) }, fd);
+        drop(new_efd);
+        h.register(&fd, EPOLLIN).unwrap();
+        // NB: Keep running until a worker has picked up the new
+        // registration, then return while its handler is still running.
+        thread::sleep(Duration::from_millis(10));
+        return;
+      }
+      let n = r.fetch_add(1, Ordering::SeqCst) + 1;
+      m.fetch_max(n, Ordering::SeqCst);
+      thread::sleep(Duration::from_millis(20));
+      c.fetch_add(1, Ordering::SeqCst);
+      r.fetch_sub(1, Ordering::SeqCst);
+    }).unwrap();
+    pool.handle().register(&efd, EPOLLIN).unwrap();
+    thread::sleep(Duration::from_millis(150));
+    pool.handle().deregister(&efd).unwrap();
+    pool.shutdown();
+    assert!(swapped.load(Ordering::SeqCst));
+    assert!(calls.load(Ordering::SeqCst) > 1);
+    assert_eq!(max_running.load(Ordering::SeqCst), 1);
+  }
+}
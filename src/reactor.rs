@@ -0,0 +1,332 @@
+//! A single-threaded event loop on top of `Epoll`.
+
+use crate::epoll::{Events, Registry, Token};
+
+use std::cmp::{Reverse};
+use std::collections::{BinaryHeap, HashMap, VecDeque};
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::{AsRawFd};
+use std::time::{Duration, Instant};
+
+/// A handler for readiness events on a file descriptor registered in a
+/// `Reactor`; implemented for closures `FnMut(&mut Reactor, Token, Events)`.
+pub trait FdHandler {
+  fn ready(&mut self, reactor: &mut Reactor, token: Token, events: Events);
+}
+
+impl<F: FnMut(&mut Reactor, Token, Events)> FdHandler for F {
+  fn ready(&mut self, reactor: &mut Reactor, token: Token, events: Events) {
+    (self)(reactor, token, events)
+  }
+}
+
+/// Identifies a timer in a `Reactor`.
+#[derive(Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Debug)]
+pub struct TimerId(u64);
+
+type Task = Box<dyn FnOnce(&mut Reactor)>;
+
+/// Returns `at + d`, or `None` (i.e. never) if that is not representable.
+fn deadline_after(at: Instant, d: Duration) -> Option<Instant> {
+  at.checked_add(d)
+}
+
+struct Timer {
+  // NB: `None` if the timer never fires; such timers are not in the heap.
+  deadline: Option<Instant>,
+  period:   Option<Duration>,
+  callback: Box<dyn FnMut(&mut Reactor)>,
+}
+
+/// Loop statistics, accumulated since the reactor was created.
+#[derive(Clone, Copy, Default, Debug)]
+pub struct ReactorStats {
+  /// The number of loop iterations (i.e. calls to `turn`).
+  pub iterations:       u64,
+  /// The total number of fd events dispatched.
+  pub events:           u64,
+  /// The largest number of fd events returned by a single wait.
+  pub max_events:       u64,
+  pub timers_fired:     u64,
+  pub tasks_run:        u64,
+  /// The total time spent in fd handlers, timer callbacks, and tasks.
+  pub handler_time:     Duration,
+}
+
+impl ReactorStats {
+  /// The average number of fd events per loop iteration.
+  pub fn events_per_wait(&self) -> f64 {
+    if self.iterations == 0 {
+      return 0.0;
+    }
+    self.events as f64 / self.iterations as f64
+  }
+}
+
+/// A single-threaded event loop dispatching fd readiness events to
+/// handlers, and running timers and deferred tasks.
+///
+/// Handlers, timer callbacks, and tasks all receive the `Reactor`, so they
+/// can register or cancel other work, or `stop` the loop.
+pub struct Reactor {
+  registry: Registry<Option<Box<dyn FdHandler>>>,
+  ready:    Vec<(Token, Events)>,
+  timers:   HashMap<TimerId, Timer>,
+  heap:     BinaryHeap<Reverse<(Instant, TimerId)>>,
+  next_id:  u64,
+  // NB: The timer whose callback is running, which is temporarily absent
+  // from `timers`, and whether it has been cancelled by its own callback.
+  firing:   Option<(TimerId, bool)>,
+  tasks:    VecDeque<Task>,
+  stopped:  bool,
+  stats:    ReactorStats,
+}
+
+impl Reactor {
+  pub fn new() -> Result<Reactor, Error> {
+    Reactor::with_capacity(256)
+  }
+
+  /// Creates a reactor that handles up to `max_events` fd events per wait.
+  pub fn with_capacity(max_events: usize) -> Result<Reactor, Error> {
+    Ok(Reactor{
+      registry: Registry::create(true, max_events)?,
+      ready:    Vec::with_capacity(max_events),
+      timers:   HashMap::new(),
+      heap:     BinaryHeap::new(),
+      next_id:  0,
+      firing:   None,
+      tasks:    VecDeque::new(),
+      stopped:  false,
+      stats:    ReactorStats::default(),
+    })
+  }
+
+  pub fn stats(&self) -> &ReactorStats {
+    &self.stats
+  }
+
+  /// Registers `fd` with the given `events`; `handler` is called whenever
+  /// it is ready.
+  pub fn register<F: AsRawFd + ?Sized, H: FdHandler + 'static>(&mut self, fd: &F, events: Events, handler: H) -> Result<Token, Error> {
+    self.registry.register(fd, events, Some(Box::new(handler)))
+  }
+
+  pub fn modify(&mut self, token: Token, events: Events) -> Result<(), Error> {
+    self.registry.modify(token, events)
+  }
+
+  /// Deregisters a file descriptor and drops its handler; may be called
+  /// from within the handler itself.
+  pub fn deregister(&mut self, token: Token) -> Result<(), Error> {
+    self.registry.deregister(token)?;
+    Ok(())
+  }
+
+  fn add_timer_inner(&mut self, deadline: Option<Instant>, period: Option<Duration>, callback: Box<dyn FnMut(&mut Reactor)>) -> TimerId {
+    let id = TimerId(self.next_id);
+    self.next_id += 1;
+    self.timers.insert(id, Timer{deadline, period, callback});
+    if let Some(deadline) = deadline {
+      self.heap.push(Reverse((deadline, id)));
+    }
+    id
+  }
+
+  /// Calls `callback` once, `after` from now; if `after` is too large to
+  /// represent as a deadline, the timer never fires.
+  pub fn add_timer<C: FnMut(&mut Reactor) + 'static>(&mut self, after: Duration, callback: C) -> TimerId {
+    self.add_timer_inner(deadline_after(Instant::now(), after), None, Box::new(callback))
+  }
+
+  /// Calls `callback` every `period`, starting one `period` from now.
+  ///
+  /// Missed periods (e.g. due to slow handlers) are not made up for, i.e.
+  /// the timer fires at most once per loop iteration; a zero `period` is
+  /// raised to 1ns, so that it fires on every iteration.
+  pub fn add_periodic<C: FnMut(&mut Reactor) + 'static>(&mut self, period: Duration, callback: C) -> TimerId {
+    let period = period.max(Duration::from_nanos(1));
+    self.add_timer_inner(deadline_after(Instant::now(), period), Some(period), Box::new(callback))
+  }
+
+  /// Cancels a timer; returns false if it was already fired (and was not
+  /// periodic) or cancelled.
+  pub fn cancel_timer(&mut self, id: TimerId) -> bool {
+    if let Some((firing, ref mut cancelled)) = self.firing {
+      if firing == id {
+        let was_live = !*cancelled;
+        *cancelled = true;
+        return was_live;
+      }
+    }
+    // NB: The heap entry is skipped lazily when it comes due.
+    self.timers.remove(&id).is_some()
+  }
+
+  /// Runs `task` on the next loop iteration.
+  pub fn defer<T: FnOnce(&mut Reactor) + 'static>(&mut self, task: T) {
+    self.tasks.push_back(Box::new(task));
+  }
+
+  /// Makes `run` return after the current iteration.
+  pub fn stop(&mut self) {
+    self.stopped = true;
+  }
+
+  pub fn is_stopped(&self) -> bool {
+    self.stopped
+  }
+
+  /// Runs the loop until `stop` is called.
+  ///
+  /// Note that with nothing registered and no timers or tasks pending, this
+  /// blocks forever.
+  pub fn run(&mut self) -> Result<(), Error> {
+    self.stopped = false;
+    while !self.stopped {
+      self.turn(None)?;
+    }
+    Ok(())
+  }
+
+  fn next_timeout(&mut self, max_wait: Option<Duration>) -> Option<Duration> {
+    if !self.tasks.is_empty() || self.stopped {
+      return Some(Duration::from_secs(0));
+    }
+    // NB: Drop cancelled timers from the top of the heap, so that they do
+    // not cause spurious wakeups.
+    while let Some(&Reverse((deadline, id))) = self.heap.peek() {
+      match self.timers.get(&id) {
+        Some(t) if t.deadline == Some(deadline) => break,
+        _ => { self.heap.pop(); }
+      }
+    }
+    let timer_wait = self.heap.peek().map(|&Reverse((deadline, _))| {
+      deadline.saturating_duration_since(Instant::now())
+    });
+    match (timer_wait, max_wait) {
+      (Some(a), Some(b)) => Some(a.min(b)),
+      (a, b) => a.or(b),
+    }
+  }
+
+  /// Runs a single loop iteration: waits for fd events (for at most
+  /// `max_wait`, or until the next timer is due), then dispatches fd
+  /// events, fires due timers, and runs the tasks deferred so far.
+  ///
+  /// Returns the number of fd events dispatched.
+  pub fn turn(&mut self, max_wait: Option<Duration>) -> Result<usize, Error> {
+    let timeout = self.next_timeout(max_wait);
+    self.ready.clear();
+    match self.registry.wait(timeout) {
+      Ok(ready) => {
+        for (token, _, events) in ready {
+          self.ready.push((token, events));
+        }
+      }
+      Err(e) if e.kind() == ErrorKind::Interrupted => {}
+      Err(e) => return Err(e),
+    }
+    let nevents = self.ready.len();
+    self.stats.iterations += 1;
+    self.stats.events += nevents as u64;
+    self.stats.max_events = self.stats.max_events.max(nevents as u64);
+
+    let start = Instant::now();
+    let ready = std::mem::take(&mut self.ready);
+    for &(token, events) in ready.iter() {
+      // NB: The handler is taken out of its slot while it runs; if it
+      // deregisters itself in the meantime, it is dropped afterwards.
+      let mut handler = match self.registry.get_mut(token).and_then(|h| h.take()) {
+        Some(h) => h,
+        None => continue,
+      };
+      handler.ready(self, token, events);
+      if let Some(slot) = self.registry.get_mut(token) {
+        *slot = Some(handler);
+      }
+    }
+    self.ready = ready;
+
+    let now = Instant::now();
+    while let Some(&Reverse((deadline, id))) = self.heap.peek() {
+      if deadline > now {
+        break;
+      }
+      self.heap.pop();
+      let mut timer = match self.timers.remove(&id) {
+        Some(t) if t.deadline == Some(deadline) => t,
+        Some(t) => { self.timers.insert(id, t); continue; }
+        None => continue,
+      };
+      self.firing = Some((id, timer.period.is_none()));
+      (timer.callback)(self);
+      let (_, cancelled) = self.firing.take().unwrap();
+      self.stats.timers_fired += 1;
+      if let (Some(period), false) = (timer.period, cancelled) {
+        // NB: If behind schedule, the next deadline is one period from now,
+        // which is not yet due, so this loop terminates.
+        timer.deadline = match deadline_after(deadline, period) {
+          Some(next) if next <= now => deadline_after(now, period),
+          next => next,
+        };
+        if let Some(next) = timer.deadline {
+          self.heap.push(Reverse((next, id)));
+        }
+        self.timers.insert(id, timer);
+      }
+    }
+
+    let ntasks = self.tasks.len();
+    for _ in 0 .. ntasks {
+      let task = match self.tasks.pop_front() {
+        Some(t) => t,
+        None => break,
+      };
+      task(self);
+      self.stats.tasks_run += 1;
+    }
+    self.stats.handler_time += start.elapsed();
+    Ok(nevents)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::cell::{Cell};
+  use std::rc::{Rc};
+
+  #[test]
+  fn zero_period_fires_once_per_turn() {
+    let mut reactor = Reactor::new().unwrap();
+    let count = Rc::new(Cell::new(0));
+    let c = count.clone();
+    reactor.add_periodic(Duration::from_secs(0), move |_| c.set(c.get() + 1));
+    for i in 1 ..= 3 {
+      reactor.turn(Some(Duration::from_millis(10))).unwrap();
+      assert_eq!(count.get(), i);
+    }
+  }
+
+  #[test]
+  fn huge_durations_do_not_panic() {
+    let mut reactor = Reactor::new().unwrap();
+    let id = reactor.add_timer(Duration::MAX, |_| panic!("fired"));
+    reactor.add_periodic(Duration::MAX, |_| panic!("fired"));
+    reactor.turn(Some(Duration::from_millis(1))).unwrap();
+    assert!(reactor.cancel_timer(id));
+  }
+
+  #[test]
+  fn periodic_behind_schedule_is_not_made_up() {
+    let mut reactor = Reactor::new().unwrap();
+    let count = Rc::new(Cell::new(0));
+    let c = count.clone();
+    reactor.add_periodic(Duration::from_millis(1), move |_| c.set(c.get() + 1));
+    std::thread::sleep(Duration::from_millis(20));
+    reactor.turn(Some(Duration::from_secs(0))).unwrap();
+    assert_eq!(count.get(), 1);
+  }
+}
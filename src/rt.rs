@@ -0,0 +1,470 @@
+//! A minimal single-threaded async runtime over `Epoll`.
+//!
+//! Each thread that calls `block_on` gets its own executor and I/O driver.
+//! `AsyncFd`s register with the driver of the thread that created them, and
+//! must only be awaited by tasks running on that thread.
+
+use crate::epoll::{self, Events, Registry, Token};
+use crate::eventfd::{self};
+
+use std::cell::{Cell, RefCell};
+use std::collections::{VecDeque};
+use std::future::{Future};
+use std::io::{Error, ErrorKind};
+use std::os::unix::io::{AsRawFd};
+use std::pin::{Pin};
+use std::rc::{Rc};
+use std::sync::{Arc, Mutex};
+use std::task::{Context, Poll, Wake, Waker};
+
+const MAIN_TASK: usize = usize::MAX;
+
+const READ_EVENTS: Events = Events{bits: epoll::EPOLLIN.bits | epoll::EPOLLPRI.bits};
+const WRITE_EVENTS: Events = epoll::EPOLLOUT;
+const CLOSED_EVENTS: Events = Events{bits: epoll::EPOLLHUP.bits | epoll::EPOLLERR.bits};
+// NB: `EPOLLRDHUP` (the peer shut down writing) only concerns readers; a
+// writer can still fill its send buffer and must then wait.
+const READ_CLOSED_EVENTS: Events = Events{bits: CLOSED_EVENTS.bits | epoll::EPOLLRDHUP.bits};
+
+#[derive(Default)]
+struct ScheduledIo {
+  readiness:  Events,
+  reader:     Option<Waker>,
+  writer:     Option<Waker>,
+}
+
+/// State shared with `Waker`s, which may be sent to other threads.
+struct Shared {
+  queue:    Mutex<VecDeque<usize>>,
+  // NB: Registered directly in the registry's epoll under `WAKER_TOKEN`, so
+  // its events are skipped by the registry and only interrupt the wait.
+  notify:   eventfd::Waker,
+}
+
+struct TaskWaker {
+  id:     usize,
+  shared: Arc<Shared>,
+}
+
+impl Wake for TaskWaker {
+  fn wake(self: Arc<Self>) {
+    self.wake_by_ref()
+  }
+
+  fn wake_by_ref(self: &Arc<Self>) {
+    self.shared.queue.lock().unwrap().push_back(self.id);
+    let _ = self.shared.notify.wake();
+  }
+}
+
+type Task = Pin<Box<dyn Future<Output=()>>>;
+
+struct Runtime {
+  registry: RefCell<Registry<ScheduledIo>>,
+  shared:   Arc<Shared>,
+  tasks:    RefCell<Vec<Option<Task>>>,
+  free:     RefCell<Vec<usize>>,
+  running:  Cell<bool>,
+}
+
+thread_local! {
+  static RUNTIME: RefCell<Option<Rc<Runtime>>> = const { RefCell::new(None) };
+}
+
+fn runtime() -> Result<Rc<Runtime>, Error> {
+  RUNTIME.with(|rt| {
+    let mut rt = rt.borrow_mut();
+    if let Some(rt) = rt.as_ref() {
+      return Ok(rt.clone());
+    }
+    let new_rt = Rc::new(Runtime::new()?);
+    *rt = Some(new_rt.clone());
+    Ok(new_rt)
+  })
+}
+
+impl Runtime {
+  fn new() -> Result<Runtime, Error> {
+    let registry = Registry::create(true, 256)?;
+    let notify = eventfd::Waker::new(registry.epoll())?;
+    Ok(Runtime{
+      registry: RefCell::new(registry),
+      shared:   Arc::new(Shared{
+        queue:    Mutex::new(VecDeque::new()),
+        notify,
+      }),
+      tasks:    RefCell::new(Vec::new()),
+      free:     RefCell::new(Vec::new()),
+      running:  Cell::new(false),
+    })
+  }
+
+  fn waker(&self, id: usize) -> Waker {
+    Waker::from(Arc::new(TaskWaker{id, shared: self.shared.clone()}))
+  }
+
+  fn spawn(&self, task: Task) {
+    let id = match self.free.borrow_mut().pop() {
+      Some(id) => {
+        self.tasks.borrow_mut()[id] = Some(task);
+        id
+      }
+      None => {
+        let mut tasks = self.tasks.borrow_mut();
+        tasks.push(Some(task));
+        tasks.len() - 1
+      }
+    };
+    self.shared.queue.lock().unwrap().push_back(id);
+  }
+
+  fn poll_task(&self, id: usize) {
+    // NB: The task is taken out of its slot while it is polled, since it
+    // may spawn other tasks.
+    let mut task = match self.tasks.borrow_mut().get_mut(id).and_then(|t| t.take()) {
+      Some(t) => t,
+      None => return,
+    };
+    let waker = self.waker(id);
+    let mut cx = Context::from_waker(&waker);
+    match task.as_mut().poll(&mut cx) {
+      Poll::Ready(()) => self.free.borrow_mut().push(id),
+      Poll::Pending => self.tasks.borrow_mut()[id] = Some(task),
+    }
+  }
+
+  /// Waits for I/O readiness (without blocking if `block` is false), and
+  /// wakes the tasks waiting on it.
+  fn turn(&self, block: bool) -> Result<(), Error> {
+    let timeout = if block { None } else { Some(std::time::Duration::from_secs(0)) };
+    let mut wakers = Vec::new();
+    {
+      let mut registry = self.registry.borrow_mut();
+      let ready = match registry.wait(timeout) {
+        Ok(ready) => ready,
+        Err(e) if e.kind() == ErrorKind::Interrupted => return Ok(()),
+        Err(e) => return Err(e),
+      };
+      for (_, io, events) in ready {
+        io.readiness |= events;
+        if events.intersects(Direction::Read.wake_events()) {
+          wakers.extend(io.reader.take());
+        }
+        if events.intersects(Direction::Write.wake_events()) {
+          wakers.extend(io.writer.take());
+        }
+      }
+    }
+    // NB: Any task woken before the reset is already in the queue, which
+    // `block_on` drains before waiting again.
+    let _ = self.shared.notify.reset();
+    for waker in wakers {
+      waker.wake();
+    }
+    Ok(())
+  }
+}
+
+/// Runs `future` to completion on the current thread, along with any tasks
+/// spawned with `spawn_local`, driving `AsyncFd` readiness in between.
+///
+/// Panics if called from within `block_on` on the same thread, or if the
+/// runtime cannot be created.
+pub fn block_on<F: Future>(future: F) -> F::Output {
+  let rt = runtime().expect("block_on: failed to create runtime");
+  assert!(!rt.running.replace(true), "block_on: called recursively");
+  struct Reset<'a>(&'a Cell<bool>);
+  impl<'a> Drop for Reset<'a> {
+    fn drop(&mut self) {
+      self.0.set(false);
+    }
+  }
+  let _reset = Reset(&rt.running);
+  let mut future = Box::pin(future);
+  let main_waker = rt.waker(MAIN_TASK);
+  let mut main_woken = true;
+  loop {
+    if main_woken {
+      main_woken = false;
+      let mut cx = Context::from_waker(&main_waker);
+      if let Poll::Ready(out) = future.as_mut().poll(&mut cx) {
+        return out;
+      }
+    }
+    let runnable: Vec<usize> = rt.shared.queue.lock().unwrap().drain(..).collect();
+    let block = runnable.is_empty();
+    for id in runnable {
+      if id == MAIN_TASK {
+        main_woken = true;
+      } else {
+        rt.poll_task(id);
+      }
+    }
+    rt.turn(block && !main_woken).expect("block_on: epoll wait failed");
+  }
+}
+
+struct JoinState<T> {
+  output: Option<T>,
+  waker:  Option<Waker>,
+}
+
+/// A future resolving to the output of a task spawned with `spawn_local`.
+///
+/// Dropping the handle detaches the task; it keeps running.
+pub struct JoinHandle<T> {
+  state: Rc<RefCell<JoinState<T>>>,
+}
+
+impl<T> Future for JoinHandle<T> {
+  type Output = T;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<T> {
+    let mut state = self.state.borrow_mut();
+    match state.output.take() {
+      Some(out) => Poll::Ready(out),
+      None => {
+        state.waker = Some(cx.waker().clone());
+        Poll::Pending
+      }
+    }
+  }
+}
+
+/// Spawns `future` as a task on the current thread's executor; it runs
+/// while the thread is inside `block_on`.
+///
+/// Panics if the runtime cannot be created.
+pub fn spawn_local<F: Future + 'static>(future: F) -> JoinHandle<F::Output> {
+  let rt = runtime().expect("spawn_local: failed to create runtime");
+  let state = Rc::new(RefCell::new(JoinState{output: None, waker: None}));
+  let task_state = state.clone();
+  rt.spawn(Box::pin(async move {
+    let out = future.await;
+    let mut state = task_state.borrow_mut();
+    state.output = Some(out);
+    if let Some(waker) = state.waker.take() {
+      waker.wake();
+    }
+  }));
+  JoinHandle{state}
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+enum Direction {
+  Read,
+  Write,
+}
+
+impl Direction {
+  fn events(self) -> Events {
+    match self {
+      Direction::Read => READ_EVENTS,
+      Direction::Write => WRITE_EVENTS,
+    }
+  }
+
+  /// The events that wake (and resolve) a readiness future.
+  fn wake_events(self) -> Events {
+    match self {
+      Direction::Read => READ_EVENTS | READ_CLOSED_EVENTS,
+      Direction::Write => WRITE_EVENTS | CLOSED_EVENTS,
+    }
+  }
+}
+
+/// A file descriptor registered with the current thread's I/O driver, with
+/// readiness futures.
+///
+/// The file descriptor is registered edge-triggered, and must be in
+/// nonblocking mode. Readiness is cached: after `readable` or `writable`
+/// resolves, it stays ready until `ReadyGuard::clear_ready` is called,
+/// which should be done once an operation fails with `WouldBlock`.
+pub struct AsyncFd<T: AsRawFd> {
+  inner:  Option<T>,
+  token:  Token,
+  rt:     Rc<Runtime>,
+}
+
+impl<T: AsRawFd> Drop for AsyncFd<T> {
+  fn drop(&mut self) {
+    let _ = self.rt.registry.borrow_mut().deregister(self.token);
+  }
+}
+
+impl<T: AsRawFd> AsyncFd<T> {
+  pub fn new(inner: T) -> Result<AsyncFd<T>, Error> {
+    let rt = runtime()?;
+    let events = epoll::EPOLLIN | epoll::EPOLLPRI | epoll::EPOLLOUT | epoll::EPOLLRDHUP | epoll::EPOLLET;
+    let token = rt.registry.borrow_mut().register(&inner, events, ScheduledIo::default())?;
+    Ok(AsyncFd{inner: Some(inner), token, rt})
+  }
+
+  pub fn get_ref(&self) -> &T {
+    self.inner.as_ref().unwrap()
+  }
+
+  pub fn get_mut(&mut self) -> &mut T {
+    self.inner.as_mut().unwrap()
+  }
+
+  /// Deregisters the file descriptor and returns the inner value.
+  pub fn into_inner(mut self) -> T {
+    self.inner.take().unwrap()
+  }
+
+  /// Waits until the file descriptor is readable (or closed, or in error).
+  pub fn readable(&self) -> Readiness<'_, T> {
+    Readiness{fd: self, dir: Direction::Read}
+  }
+
+  /// Waits until the file descriptor is writable (or hung up, or in error);
+  /// the peer shutting down only its write side does not count.
+  pub fn writable(&self) -> Readiness<'_, T> {
+    Readiness{fd: self, dir: Direction::Write}
+  }
+
+  /// Calls `f` whenever the file descriptor is readable, until it returns
+  /// anything other than a `WouldBlock` error.
+  pub async fn read_with<R, F: FnMut(&T) -> Result<R, Error>>(&self, mut f: F) -> Result<R, Error> {
+    loop {
+      let mut guard = self.readable().await?;
+      match f(self.get_ref()) {
+        Err(e) if e.kind() == ErrorKind::WouldBlock => guard.clear_ready(),
+        res => return res,
+      }
+    }
+  }
+
+  /// Calls `f` whenever the file descriptor is writable, until it returns
+  /// anything other than a `WouldBlock` error.
+  pub async fn write_with<R, F: FnMut(&T) -> Result<R, Error>>(&self, mut f: F) -> Result<R, Error> {
+    loop {
+      let mut guard = self.writable().await?;
+      match f(self.get_ref()) {
+        Err(e) if e.kind() == ErrorKind::WouldBlock => guard.clear_ready(),
+        res => return res,
+      }
+    }
+  }
+}
+
+/// The future returned by `AsyncFd::readable` and `AsyncFd::writable`.
+pub struct Readiness<'a, T: AsRawFd> {
+  fd:   &'a AsyncFd<T>,
+  dir:  Direction,
+}
+
+impl<'a, T: AsRawFd> Future for Readiness<'a, T> {
+  type Output = Result<ReadyGuard<'a, T>, Error>;
+
+  fn poll(self: Pin<&mut Self>, cx: &mut Context) -> Poll<Self::Output> {
+    let fd = self.fd;
+    let dir = self.dir;
+    let mut registry = fd.rt.registry.borrow_mut();
+    let io = match registry.get_mut(fd.token) {
+      Some(io) => io,
+      None => return Poll::Ready(Err(Error::new(ErrorKind::NotFound, "AsyncFd is not registered"))),
+    };
+    if io.readiness.intersects(dir.wake_events()) {
+      return Poll::Ready(Ok(ReadyGuard{fd, dir}));
+    }
+    let slot = match dir {
+      Direction::Read => &mut io.reader,
+      Direction::Write => &mut io.writer,
+    };
+    *slot = Some(cx.waker().clone());
+    Poll::Pending
+  }
+}
+
+/// Returned by a resolved readiness future.
+pub struct ReadyGuard<'a, T: AsRawFd> {
+  fd:   &'a AsyncFd<T>,
+  dir:  Direction,
+}
+
+impl<'a, T: AsRawFd> ReadyGuard<'a, T> {
+  pub fn get_inner(&self) -> &'a T {
+    self.fd.get_ref()
+  }
+
+  /// The cached readiness, e.g. to check `Events::is_hangup`.
+  pub fn events(&self) -> Events {
+    let registry = self.fd.rt.registry.borrow();
+    registry.get(self.fd.token).map_or(Events::empty(), |io| io.readiness)
+  }
+
+  /// Clears the cached readiness for this direction, so that the next
+  /// readiness future waits for a new edge. Hangup and error readiness are
+  /// not cleared.
+  pub fn clear_ready(&mut self) {
+    let mut registry = self.fd.rt.registry.borrow_mut();
+    if let Some(io) = registry.get_mut(self.fd.token) {
+      io.readiness.remove(self.dir.events());
+    }
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::io::{Read, Write};
+  use std::net::{Shutdown};
+  use std::os::unix::net::{UnixStream};
+
+  #[test]
+  fn rdhup_does_not_make_writable() {
+    let (a, b) = UnixStream::pair().unwrap();
+    a.set_nonblocking(true).unwrap();
+    b.shutdown(Shutdown::Write).unwrap();
+    block_on(async {
+      let fd = AsyncFd::new(a).unwrap();
+      let buf = [0u8; 4096];
+      loop {
+        let mut guard = fd.writable().await.unwrap();
+        match guard.get_inner().write(&buf) {
+          Ok(_) => continue,
+          Err(e) if e.kind() == ErrorKind::WouldBlock => { guard.clear_ready(); break; }
+          Err(e) => panic!("write: {}", e),
+        }
+      }
+      let pending = std::future::poll_fn(|cx| {
+        Poll::Ready(Pin::new(&mut fd.writable()).poll(cx).is_pending())
+      }).await;
+      assert!(pending);
+      // NB: Readers are still woken by the peer's shutdown.
+      let guard = fd.readable().await.unwrap();
+      assert!(guard.events().contains(epoll::EPOLLRDHUP));
+      let mut rbuf = [0u8; 16];
+      assert_eq!(guard.get_inner().read(&mut rbuf).unwrap(), 0);
+    });
+    drop(b);
+  }
+
+  #[test]
+  fn wake_from_another_thread_unblocks_block_on() {
+    let done = Arc::new(Mutex::new(false));
+    let polls = block_on(std::future::poll_fn({
+      let done = done.clone();
+      let mut polls = 0;
+      move |cx| {
+        polls += 1;
+        if *done.lock().unwrap() {
+          return Poll::Ready(polls);
+        }
+        if polls == 1 {
+          let (done, waker) = (done.clone(), cx.waker().clone());
+          std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(10));
+            *done.lock().unwrap() = true;
+            waker.wake();
+          });
+        }
+        Poll::Pending
+      }
+    }));
+    assert_eq!(polls, 2);
+  }
+}
@@ -0,0 +1,131 @@
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::mem::{MaybeUninit};
+use std::ptr::{null};
+
+/// A set of signals (`sigset_t`), given by their numbers, e.g.
+/// `libc::SIGTERM`.
+#[derive(Clone, Copy)]
+pub struct SigSet {
+  raw:  libc::sigset_t,
+}
+
+impl Default for SigSet {
+  fn default() -> SigSet {
+    SigSet::empty()
+  }
+}
+
+impl SigSet {
+  pub fn empty() -> SigSet {
+    let mut raw = MaybeUninit::uninit();
+    unsafe {
+      libc::sigemptyset(raw.as_mut_ptr());
+      SigSet{raw: raw.assume_init()}
+    }
+  }
+
+  pub fn full() -> SigSet {
+    let mut raw = MaybeUninit::uninit();
+    unsafe {
+      libc::sigfillset(raw.as_mut_ptr());
+      SigSet{raw: raw.assume_init()}
+    }
+  }
+
+  pub fn from_signals(signals: &[libc::c_int]) -> Result<SigSet, Error> {
+    let mut set = SigSet::empty();
+    for &sig in signals.iter() {
+      set.insert(sig)?;
+    }
+    Ok(set)
+  }
+
+  pub fn insert(&mut self, sig: libc::c_int) -> Result<(), Error> {
+    let res = unsafe { libc::sigaddset(&mut self.raw, sig) };
+    if res != 0 {
+      return Err(Error::new(ErrorKind::InvalidInput, format!("invalid signal: {}", sig)));
+    }
+    Ok(())
+  }
+
+  pub fn remove(&mut self, sig: libc::c_int) -> Result<(), Error> {
+    let res = unsafe { libc::sigdelset(&mut self.raw, sig) };
+    if res != 0 {
+      return Err(Error::new(ErrorKind::InvalidInput, format!("invalid signal: {}", sig)));
+    }
+    Ok(())
+  }
+
+  pub fn contains(&self, sig: libc::c_int) -> bool {
+    unsafe { libc::sigismember(&self.raw, sig) == 1 }
+  }
+
+  /// Returns an iterator over the (standard and real-time) signals in the
+  /// set.
+  pub fn iter(&self) -> impl Iterator<Item=libc::c_int> + '_ {
+    (1 .. 65).filter(move |&sig| self.contains(sig))
+  }
+
+  pub fn as_raw(&self) -> &libc::sigset_t {
+    &self.raw
+  }
+
+  pub fn as_raw_mut(&mut self) -> &mut libc::sigset_t {
+    &mut self.raw
+  }
+}
+
+impl fmt::Debug for SigSet {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    f.debug_set().entries(self.iter()).finish()
+  }
+}
+
+/// How `sigprocmask`/`pthread_sigmask` combine the given set with the
+/// current mask.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SigmaskHow {
+  /// Add the set to the current mask (`SIG_BLOCK`).
+  Block,
+  /// Remove the set from the current mask (`SIG_UNBLOCK`).
+  Unblock,
+  /// Replace the current mask with the set (`SIG_SETMASK`).
+  SetMask,
+}
+
+impl SigmaskHow {
+  fn to_raw(self) -> libc::c_int {
+    match self {
+      SigmaskHow::Block => libc::SIG_BLOCK,
+      SigmaskHow::Unblock => libc::SIG_UNBLOCK,
+      SigmaskHow::SetMask => libc::SIG_SETMASK,
+    }
+  }
+}
+
+/// Changes the signal mask of the calling process, returning the previous
+/// mask. If `set` is `None`, the mask is only queried.
+///
+/// In multithreaded programs, prefer `pthread_sigmask`.
+pub fn sigprocmask(how: SigmaskHow, set: Option<&SigSet>) -> Result<SigSet, Error> {
+  let mut prev = SigSet::empty();
+  let set = set.map_or(null(), |s| &s.raw as *const _);
+  let res = unsafe { libc::sigprocmask(how.to_raw(), set, &mut prev.raw) };
+  if res != 0 {
+    return Err(Error::last_os_error());
+  }
+  Ok(prev)
+}
+
+/// Changes the signal mask of the calling thread, returning the previous
+/// mask. If `set` is `None`, the mask is only queried.
+pub fn pthread_sigmask(how: SigmaskHow, set: Option<&SigSet>) -> Result<SigSet, Error> {
+  let mut prev = SigSet::empty();
+  let set = set.map_or(null(), |s| &s.raw as *const _);
+  let res = unsafe { libc::pthread_sigmask(how.to_raw(), set, &mut prev.raw) };
+  if res != 0 {
+    return Err(Error::from_raw_os_error(res));
+  }
+  Ok(prev)
+}
@@ -0,0 +1,175 @@
+use crate::signal::{SigSet, SigmaskHow, pthread_sigmask};
+
+use std::io::{Error, ErrorKind};
+use std::mem::{size_of, zeroed};
+use std::ops::{BitOr};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(transparent)]
+pub struct SignalFdFlags {
+  pub bits: libc::c_int,
+}
+
+impl SignalFdFlags {
+  #[inline]
+  pub fn empty() -> SignalFdFlags {
+    SignalFdFlags{bits: 0}
+  }
+
+  #[inline]
+  pub fn bits(&self) -> libc::c_int {
+    self.bits
+  }
+}
+
+impl BitOr for SignalFdFlags {
+  type Output = SignalFdFlags;
+
+  #[inline]
+  fn bitor(self, rhs: SignalFdFlags) -> SignalFdFlags {
+    SignalFdFlags{bits: self.bits | rhs.bits}
+  }
+}
+
+/// Set `FD_CLOEXEC` on the new file descriptor.
+pub const SFD_CLOEXEC: SignalFdFlags = SignalFdFlags{bits: libc::SFD_CLOEXEC};
+
+/// Set `O_NONBLOCK` on the new file descriptor.
+pub const SFD_NONBLOCK: SignalFdFlags = SignalFdFlags{bits: libc::SFD_NONBLOCK};
+
+/// A signal read from a `SignalFd`, decoded from `signalfd_siginfo`.
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub struct SigInfo {
+  /// The signal number, e.g. `libc::SIGCHLD`.
+  pub signal: libc::c_int,
+  /// The signal code, e.g. `libc::SI_USER`, or `libc::CLD_EXITED` for
+  /// `SIGCHLD`.
+  pub code:   libc::c_int,
+  pub errno:  libc::c_int,
+  /// The sending process (for `kill`, `sigqueue`), or the child (for
+  /// `SIGCHLD`).
+  pub pid:    libc::pid_t,
+  /// The real UID of the sending process, or of the child.
+  pub uid:    libc::uid_t,
+  /// The exit status or signal of the child (for `SIGCHLD`).
+  pub status: libc::c_int,
+  /// The value passed with `sigqueue`.
+  pub int:    libc::c_int,
+  pub ptr:    u64,
+  /// The file descriptor (for `SIGIO`).
+  pub fd:     libc::c_int,
+  /// The address that caused a fault (for `SIGSEGV`, `SIGBUS`, etc).
+  pub addr:   u64,
+}
+
+impl SigInfo {
+  fn from_raw(raw: &libc::signalfd_siginfo) -> SigInfo {
+    SigInfo{
+      signal: raw.ssi_signo as _,
+      code:   raw.ssi_code,
+      errno:  raw.ssi_errno,
+      pid:    raw.ssi_pid as _,
+      uid:    raw.ssi_uid,
+      status: raw.ssi_status,
+      int:    raw.ssi_int,
+      ptr:    raw.ssi_ptr,
+      fd:     raw.ssi_fd,
+      addr:   raw.ssi_addr,
+    }
+  }
+}
+
+/// A file descriptor that receives signals, which becomes readable (e.g.
+/// `EPOLLIN` in an `Epoll`) when one of its signals is pending.
+#[derive(Debug)]
+pub struct SignalFd {
+  fd: RawFd,
+}
+
+impl Drop for SignalFd {
+  fn drop(&mut self) {
+    unsafe { libc::close(self.fd); }
+  }
+}
+
+impl SignalFd {
+  /// Blocks `signals` in the calling thread (so that they are not delivered
+  /// to handlers), and creates a new signalfd receiving them.
+  ///
+  /// Note that the signals should also be blocked in every other thread, or
+  /// else they may be delivered there instead; the simplest way is to call
+  /// this before spawning any threads, which inherit the signal mask.
+  ///
+  /// ## Notes
+  ///
+  /// * `signalfd4()` is the underlying syscall.
+  pub fn create(signals: &SigSet, flags: SignalFdFlags) -> Result<SignalFd, Error> {
+    pthread_sigmask(SigmaskHow::Block, Some(signals))?;
+    let fd = unsafe { libc::signalfd(-1, signals.as_raw(), flags.bits()) };
+    if fd < 0 {
+      return Err(Error::last_os_error());
+    }
+    Ok(SignalFd{fd})
+  }
+
+  /// Replaces the set of signals received by this signalfd, blocking the
+  /// new signals in the calling thread; previously blocked signals are left
+  /// blocked.
+  pub fn set_mask(&self, signals: &SigSet) -> Result<(), Error> {
+    pthread_sigmask(SigmaskHow::Block, Some(signals))?;
+    let fd = unsafe { libc::signalfd(self.fd, signals.as_raw(), 0) };
+    if fd < 0 {
+      return Err(Error::last_os_error());
+    }
+    Ok(())
+  }
+
+  /// Reads one pending signal, blocking until there is one if the signalfd
+  /// is not `SFD_NONBLOCK`.
+  pub fn read(&self) -> Result<SigInfo, Error> {
+    let mut raw: libc::signalfd_siginfo = unsafe { zeroed() };
+    let len = size_of::<libc::signalfd_siginfo>();
+    let res = unsafe { libc::read(self.fd, &mut raw as *mut _ as *mut _, len) };
+    if res < 0 {
+      return Err(Error::last_os_error());
+    }
+    if res as usize != len {
+      return Err(Error::new(ErrorKind::UnexpectedEof, "short signalfd read"));
+    }
+    Ok(SigInfo::from_raw(&raw))
+  }
+
+  /// Reads all pending signals without blocking; the signalfd must be
+  /// `SFD_NONBLOCK`.
+  pub fn read_all(&self) -> Result<Vec<SigInfo>, Error> {
+    let mut infos = Vec::new();
+    loop {
+      match self.read() {
+        Ok(info) => infos.push(info),
+        Err(e) if e.kind() == ErrorKind::WouldBlock => return Ok(infos),
+        Err(e) => return Err(e),
+      }
+    }
+  }
+}
+
+impl AsRawFd for SignalFd {
+  fn as_raw_fd(&self) -> RawFd {
+    self.fd
+  }
+}
+
+impl IntoRawFd for SignalFd {
+  fn into_raw_fd(self) -> RawFd {
+    let fd = self.fd;
+    std::mem::forget(self);
+    fd
+  }
+}
+
+impl FromRawFd for SignalFd {
+  unsafe fn from_raw_fd(fd: RawFd) -> SignalFd {
+    SignalFd{fd}
+  }
+}
@@ -0,0 +1,667 @@
+//! Spawning child processes with `fork` and `execve`, with an explicit fd
+//! table and credentials applied to the child only.
+
+use crate::mode::{Mode};
+use crate::pidfd::{ExitStatus, PidFd, PidFdFlags};
+
+use std::collections::{BTreeMap};
+use std::ffi::{CString, OsStr, OsString};
+use std::fmt;
+use std::io::{Error, ErrorKind};
+use std::mem::{zeroed};
+use std::os::unix::ffi::{OsStrExt};
+use std::os::unix::io::{AsRawFd, RawFd};
+use std::path::{Path};
+use std::ptr::{null, null_mut};
+
+/// A resource for `Command::rlimit`.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Resource {
+  As,
+  Core,
+  Cpu,
+  Data,
+  Fsize,
+  Locks,
+  Memlock,
+  Msgqueue,
+  Nice,
+  Nofile,
+  Nproc,
+  Rss,
+  Rtprio,
+  Rttime,
+  Sigpending,
+  Stack,
+}
+
+impl Resource {
+  fn to_raw(self) -> libc::c_int {
+    (match self {
+      Resource::As => libc::RLIMIT_AS,
+      Resource::Core => libc::RLIMIT_CORE,
+      Resource::Cpu => libc::RLIMIT_CPU,
+      Resource::Data => libc::RLIMIT_DATA,
+      Resource::Fsize => libc::RLIMIT_FSIZE,
+      Resource::Locks => libc::RLIMIT_LOCKS,
+      Resource::Memlock => libc::RLIMIT_MEMLOCK,
+      Resource::Msgqueue => libc::RLIMIT_MSGQUEUE,
+      Resource::Nice => libc::RLIMIT_NICE,
+      Resource::Nofile => libc::RLIMIT_NOFILE,
+      Resource::Nproc => libc::RLIMIT_NPROC,
+      Resource::Rss => libc::RLIMIT_RSS,
+      Resource::Rtprio => libc::RLIMIT_RTPRIO,
+      Resource::Rttime => libc::RLIMIT_RTTIME,
+      Resource::Sigpending => libc::RLIMIT_SIGPENDING,
+      Resource::Stack => libc::RLIMIT_STACK,
+    }) as libc::c_int
+  }
+}
+
+/// The step of the child's setup (before `execve`) that failed.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(u32)]
+pub enum SpawnStage {
+  Sigmask = 1,
+  Setsid,
+  Pdeathsig,
+  Fds,
+  Rlimit,
+  Setgroups,
+  Setgid,
+  Setuid,
+  Chdir,
+  Exec,
+}
+
+impl SpawnStage {
+  fn from_raw(raw: u32) -> Option<SpawnStage> {
+    Some(match raw {
+      1 => SpawnStage::Sigmask,
+      2 => SpawnStage::Setsid,
+      3 => SpawnStage::Pdeathsig,
+      4 => SpawnStage::Fds,
+      5 => SpawnStage::Rlimit,
+      6 => SpawnStage::Setgroups,
+      7 => SpawnStage::Setgid,
+      8 => SpawnStage::Setuid,
+      9 => SpawnStage::Chdir,
+      10 => SpawnStage::Exec,
+      _ => return None,
+    })
+  }
+
+  fn name(self) -> &'static str {
+    match self {
+      SpawnStage::Sigmask => "sigprocmask",
+      SpawnStage::Setsid => "setsid",
+      SpawnStage::Pdeathsig => "prctl(PR_SET_PDEATHSIG)",
+      SpawnStage::Fds => "fd setup",
+      SpawnStage::Rlimit => "setrlimit",
+      SpawnStage::Setgroups => "setgroups",
+      SpawnStage::Setgid => "setgid",
+      SpawnStage::Setuid => "setuid",
+      SpawnStage::Chdir => "chdir",
+      SpawnStage::Exec => "execve",
+    }
+  }
+}
+
+/// An error from the child's setup, reported back to the parent.
+#[derive(Debug)]
+pub struct SpawnError {
+  pub stage: SpawnStage,
+  pub error: Error,
+}
+
+impl fmt::Display for SpawnError {
+  fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+    write!(f, "spawn: {} failed in child: {}", self.stage.name(), self.error)
+  }
+}
+
+impl std::error::Error for SpawnError {
+  fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+    Some(&self.error)
+  }
+}
+
+/// Reports a failed setup step (with the current `errno`) to the parent,
+/// and exits; only makes async-signal-safe calls.
+fn child_fail(err_w: RawFd, stage: SpawnStage) -> ! {
+  unsafe {
+    let errno = *libc::__errno_location();
+    let mut msg = [0u8; 8];
+    msg[ .. 4].copy_from_slice(&(stage as u32).to_ne_bytes());
+    msg[4 .. ].copy_from_slice(&errno.to_ne_bytes());
+    libc::write(err_w, msg.as_ptr() as *const _, msg.len());
+    libc::_exit(127)
+  }
+}
+
+fn cstring<S: AsRef<OsStr>>(s: S) -> Result<CString, Error> {
+  CString::new(s.as_ref().as_bytes())
+    .map_err(|_| Error::new(ErrorKind::InvalidInput, "argument contains a nul byte"))
+}
+
+/// A builder for spawning a child process, similar to
+/// `std::process::Command`, but with an explicit fd table.
+///
+/// By default, the child inherits fds 0, 1, and 2, and the environment of
+/// the parent; every other fd is closed in the child, whether or not it is
+/// `FD_CLOEXEC`. The child's signal mask is cleared, and `SIGPIPE` is reset
+/// to its default disposition.
+#[derive(Clone, Debug)]
+pub struct Command {
+  program:    OsString,
+  args:       Vec<OsString>,
+  env_clear:  bool,
+  env:        BTreeMap<OsString, Option<OsString>>,
+  cwd:        Option<OsString>,
+  fds:        BTreeMap<RawFd, RawFd>,
+  uid:        Option<u32>,
+  gid:        Option<u32>,
+  groups:     Option<Vec<u32>>,
+  umask:      Option<Mode>,
+  setsid:     bool,
+  pdeathsig:  Option<libc::c_int>,
+  rlimits:    Vec<(Resource, u64, u64)>,
+}
+
+impl Command {
+  /// Runs `program`, which is looked up in `PATH` (of the child's
+  /// environment) if it does not contain a `/`; `argv[0]` is `program`.
+  pub fn new<S: AsRef<OsStr>>(program: S) -> Command {
+    let program = program.as_ref().to_owned();
+    Command{
+      args:       vec![program.clone()],
+      program,
+      env_clear:  false,
+      env:        BTreeMap::new(),
+      cwd:        None,
+      fds:        (0 .. 3).map(|fd| (fd, fd)).collect(),
+      uid:        None,
+      gid:        None,
+      groups:     None,
+      umask:      None,
+      setsid:     false,
+      pdeathsig:  None,
+      rlimits:    Vec::new(),
+    }
+  }
+
+  pub fn arg<S: AsRef<OsStr>>(&mut self, arg: S) -> &mut Command {
+    self.args.push(arg.as_ref().to_owned());
+    self
+  }
+
+  pub fn args<I: IntoIterator<Item=S>, S: AsRef<OsStr>>(&mut self, args: I) -> &mut Command {
+    for arg in args {
+      self.arg(arg);
+    }
+    self
+  }
+
+  pub fn env<K: AsRef<OsStr>, V: AsRef<OsStr>>(&mut self, key: K, val: V) -> &mut Command {
+    self.env.insert(key.as_ref().to_owned(), Some(val.as_ref().to_owned()));
+    self
+  }
+
+  pub fn env_remove<K: AsRef<OsStr>>(&mut self, key: K) -> &mut Command {
+    self.env.insert(key.as_ref().to_owned(), None);
+    self
+  }
+
+  /// Starts the child with an empty environment (plus any variables set
+  /// with `env`).
+  pub fn env_clear(&mut self) -> &mut Command {
+    self.env_clear = true;
+    self.env.clear();
+    self
+  }
+
+  pub fn current_dir<P: AsRef<Path>>(&mut self, dir: P) -> &mut Command {
+    self.cwd = Some(dir.as_ref().as_os_str().to_owned());
+    self
+  }
+
+  /// Makes `fd` (in the parent) available as `child_fd` in the child,
+  /// replacing any previous mapping for `child_fd`.
+  ///
+  /// `fd` must stay open until `spawn` returns.
+  pub fn fd<F: AsRawFd + ?Sized>(&mut self, child_fd: RawFd, fd: &F) -> &mut Command {
+    self.fds.insert(child_fd, fd.as_raw_fd());
+    self
+  }
+
+  /// Leaves `child_fd` closed in the child (including 0, 1, or 2).
+  pub fn close_fd(&mut self, child_fd: RawFd) -> &mut Command {
+    self.fds.remove(&child_fd);
+    self
+  }
+
+  /// Removes every mapping from the fd table, including 0, 1, and 2.
+  pub fn clear_fds(&mut self) -> &mut Command {
+    self.fds.clear();
+    self
+  }
+
+  pub fn uid(&mut self, uid: u32) -> &mut Command {
+    self.uid = Some(uid);
+    self
+  }
+
+  pub fn gid(&mut self, gid: u32) -> &mut Command {
+    self.gid = Some(gid);
+    self
+  }
+
+  /// Sets the supplementary groups of the child; when changing `uid` and
+  /// `gid` from root, this should usually be set too, since otherwise the
+  /// child keeps the parent's groups.
+  pub fn groups(&mut self, groups: &[u32]) -> &mut Command {
+    self.groups = Some(groups.to_owned());
+    self
+  }
+
+  pub fn umask(&mut self, mask: Mode) -> &mut Command {
+    self.umask = Some(mask);
+    self
+  }
+
+  /// Runs the child in a new session (and process group).
+  pub fn setsid(&mut self, setsid: bool) -> &mut Command {
+    self.setsid = setsid;
+    self
+  }
+
+  /// Sends `signal` to the child when the parent thread that spawned it
+  /// exits.
+  pub fn pdeathsig(&mut self, signal: libc::c_int) -> &mut Command {
+    self.pdeathsig = Some(signal);
+    self
+  }
+
+  /// Sets a resource limit in the child; `libc::RLIM_INFINITY` means
+  /// unlimited.
+  pub fn rlimit(&mut self, resource: Resource, soft: u64, hard: u64) -> &mut Command {
+    self.rlimits.push((resource, soft, hard));
+    self
+  }
+
+  fn envp(&self) -> Result<Vec<CString>, Error> {
+    let mut vars: BTreeMap<OsString, OsString> = BTreeMap::new();
+    if !self.env_clear {
+      vars.extend(std::env::vars_os());
+    }
+    for (k, v) in self.env.iter() {
+      match v {
+        Some(v) => { vars.insert(k.clone(), v.clone()); }
+        None => { vars.remove(k); }
+      }
+    }
+    vars.into_iter().map(|(k, v)| {
+      let mut kv = k;
+      kv.push("=");
+      kv.push(v);
+      cstring(kv)
+    }).collect()
+  }
+
+  fn resolve(&self) -> Result<CString, Error> {
+    if self.program.as_bytes().contains(&b'/') {
+      return cstring(&self.program);
+    }
+    let path = match self.env.get(OsStr::new("PATH")) {
+      Some(path) => path.clone(),
+      None if self.env_clear => None,
+      None => std::env::var_os("PATH"),
+    };
+    let path = path.unwrap_or_else(|| OsString::from("/usr/bin:/bin"));
+    for dir in std::env::split_paths(&path) {
+      let candidate = cstring(dir.join(&self.program))?;
+      if unsafe { libc::access(candidate.as_ptr(), libc::X_OK) } == 0 {
+        return Ok(candidate);
+      }
+    }
+    Err(Error::new(ErrorKind::NotFound, format!("{:?} not found in PATH", self.program)))
+  }
+
+  /// Spawns the child; errors in the child before `execve` (including
+  /// `execve` itself) are returned as a `SpawnError`, after reaping the
+  /// child.
+  pub fn spawn(&self) -> Result<Child, Error> {
+    // NB: Everything the child needs is allocated before `fork`, since the
+    // child may only make async-signal-safe calls.
+    let path = self.resolve()?;
+    let args: Vec<CString> = self.args.iter().map(cstring).collect::<Result<_, _>>()?;
+    let mut argv: Vec<*const libc::c_char> = args.iter().map(|a| a.as_ptr()).collect();
+    argv.push(null());
+    let envs = self.envp()?;
+    let mut envp: Vec<*const libc::c_char> = envs.iter().map(|e| e.as_ptr()).collect();
+    envp.push(null());
+    let cwd = match self.cwd {
+      Some(ref cwd) => Some(cstring(cwd)?),
+      None => None,
+    };
+    let fds: Vec<(RawFd, RawFd)> = self.fds.iter().map(|(&c, &p)| (c, p)).collect();
+    let mut tmp_fds: Vec<RawFd> = vec![-1; fds.len()];
+    let rlimits: Vec<(libc::c_int, libc::rlimit)> = self.rlimits.iter().map(|&(res, soft, hard)| {
+      (res.to_raw(), libc::rlimit{rlim_cur: soft as libc::rlim_t, rlim_max: hard as libc::rlim_t})
+    }).collect();
+    let mut nofile: libc::rlimit = unsafe { zeroed() };
+    unsafe { libc::getrlimit(libc::RLIMIT_NOFILE, &mut nofile); }
+    let empty_mask = crate::signal::SigSet::empty();
+    let ppid = unsafe { libc::getpid() };
+
+    let mut pipe = [-1; 2];
+    if unsafe { libc::pipe2(pipe.as_mut_ptr(), libc::O_CLOEXEC) } != 0 {
+      return Err(Error::last_os_error());
+    }
+    let (err_r, err_w) = (pipe[0], pipe[1]);
+    let high = fds.iter().map(|&(c, p)| c.max(p)).chain(Some(err_w)).max().unwrap() + 1;
+
+    let pid = unsafe { libc::fork() };
+    if pid < 0 {
+      let e = Error::last_os_error();
+      unsafe { libc::close(err_r); libc::close(err_w); }
+      return Err(e);
+    }
+    if pid == 0 {
+      unsafe {
+        let fail = |stage| child_fail(err_w, stage);
+        if libc::sigprocmask(libc::SIG_SETMASK, empty_mask.as_raw(), null_mut()) != 0 {
+          fail(SpawnStage::Sigmask);
+        }
+        libc::signal(libc::SIGPIPE, libc::SIG_DFL);
+        if self.setsid && libc::setsid() < 0 {
+          fail(SpawnStage::Setsid);
+        }
+        if let Some(sig) = self.pdeathsig {
+          if libc::prctl(libc::PR_SET_PDEATHSIG, sig as libc::c_ulong, 0 as libc::c_ulong, 0 as libc::c_ulong, 0 as libc::c_ulong) != 0 {
+            fail(SpawnStage::Pdeathsig);
+          }
+          // NB: The parent may have exited before `prctl`.
+          if libc::getppid() != ppid {
+            libc::_exit(127);
+          }
+        }
+
+        // NB: First move the error pipe and every source fd above all the
+        // fd numbers involved, so that `dup2` into the table cannot clobber
+        // a source that is yet to be duplicated.
+        let err_fd = libc::fcntl(err_w, libc::F_DUPFD_CLOEXEC, high);
+        if err_fd < 0 {
+          fail(SpawnStage::Fds);
+        }
+        // NB: From here on, the error pipe lives at `err_w`, above every
+        // fd in the table.
+        let err_w = err_fd;
+        let fail = |stage| child_fail(err_w, stage);
+        for (i, &(_, parent_fd)) in fds.iter().enumerate() {
+          let fd = libc::fcntl(parent_fd, libc::F_DUPFD_CLOEXEC, high);
+          if fd < 0 {
+            fail(SpawnStage::Fds);
+          }
+          tmp_fds[i] = fd;
+        }
+        for (i, &(child_fd, _)) in fds.iter().enumerate() {
+          if libc::dup2(tmp_fds[i], child_fd) < 0 {
+            fail(SpawnStage::Fds);
+          }
+        }
+        let mut next = 0;
+        for &(child_fd, _) in fds.iter() {
+          for fd in next .. child_fd {
+            libc::close(fd);
+          }
+          next = child_fd + 1;
+        }
+        let close_range = |lo: RawFd, hi: RawFd| {
+          if lo > hi {
+            return;
+          }
+          let res = libc::syscall(libc::SYS_close_range, lo as libc::c_uint, hi as libc::c_uint, 0 as libc::c_uint);
+          if res != 0 {
+            // NB: `close_range` is only available since Linux 5.9.
+            let hi = hi.min(nofile.rlim_cur.min(RawFd::MAX as libc::rlim_t) as RawFd);
+            for fd in lo ..= hi {
+              libc::close(fd);
+            }
+          }
+        };
+        close_range(next, err_w - 1);
+        close_range(err_w + 1, RawFd::MAX);
+
+        for (resource, limit) in rlimits.iter() {
+          if libc::setrlimit(*resource as _, limit) != 0 {
+            fail(SpawnStage::Rlimit);
+          }
+        }
+        if let Some(ref groups) = self.groups {
+          if libc::setgroups(groups.len() as _, groups.as_ptr()) != 0 {
+            fail(SpawnStage::Setgroups);
+          }
+        }
+        if let Some(gid) = self.gid {
+          if libc::setgid(gid) != 0 {
+            fail(SpawnStage::Setgid);
+          }
+        }
+        if let Some(uid) = self.uid {
+          if libc::setuid(uid) != 0 {
+            fail(SpawnStage::Setuid);
+          }
+        }
+        if let Some(mask) = self.umask {
+          libc::umask(mask.bits as libc::mode_t);
+        }
+        if let Some(ref cwd) = cwd {
+          if libc::chdir(cwd.as_ptr()) != 0 {
+            fail(SpawnStage::Chdir);
+          }
+        }
+        libc::execve(path.as_ptr(), argv.as_ptr(), envp.as_ptr());
+        fail(SpawnStage::Exec);
+      }
+    }
+
+    unsafe { libc::close(err_w); }
+    let mut msg = [0u8; 8];
+    let mut len = 0;
+    while len < msg.len() {
+      let res = unsafe { libc::read(err_r, msg[len .. ].as_mut_ptr() as *mut _, msg.len() - len) };
+      if res < 0 {
+        if Error::last_os_error().kind() == ErrorKind::Interrupted {
+          continue;
+        }
+        break;
+      }
+      if res == 0 {
+        break;
+      }
+      len += res as usize;
+    }
+    unsafe { libc::close(err_r); }
+    let mut child = Child{pid, status: None};
+    if len == 0 {
+      return Ok(child);
+    }
+    let _ = child.wait();
+    if len != msg.len() {
+      return Err(Error::other("spawn: short read from child error pipe"));
+    }
+    let mut stage = [0u8; 4];
+    stage.copy_from_slice(&msg[ .. 4]);
+    let mut errno = [0u8; 4];
+    errno.copy_from_slice(&msg[4 .. ]);
+    let stage = match SpawnStage::from_raw(u32::from_ne_bytes(stage)) {
+      Some(stage) => stage,
+      None => return Err(Error::other("spawn: bad message from child error pipe")),
+    };
+    let error = Error::from_raw_os_error(i32::from_ne_bytes(errno));
+    Err(Error::new(error.kind(), SpawnError{stage, error}))
+  }
+}
+
+/// A child process spawned by `Command`.
+///
+/// Dropping a `Child` neither kills nor reaps the process.
+#[derive(Debug)]
+pub struct Child {
+  pid:    libc::pid_t,
+  status: Option<ExitStatus>,
+}
+
+impl Child {
+  pub fn pid(&self) -> libc::pid_t {
+    self.pid
+  }
+
+  /// Opens a pidfd for the child, e.g. to watch for its exit in an `Epoll`;
+  /// fails if the child has already been reaped.
+  pub fn pidfd(&self) -> Result<PidFd, Error> {
+    if self.status.is_some() {
+      return Err(Error::new(ErrorKind::InvalidInput, "child has already been reaped"));
+    }
+    PidFd::open(self.pid, PidFdFlags::empty())
+  }
+
+  /// Sends `signal` to the child, unless it has already been reaped.
+  pub fn kill(&self, signal: libc::c_int) -> Result<(), Error> {
+    if self.status.is_some() {
+      return Err(Error::new(ErrorKind::InvalidInput, "child has already been reaped"));
+    }
+    if unsafe { libc::kill(self.pid, signal) } != 0 {
+      return Err(Error::last_os_error());
+    }
+    Ok(())
+  }
+
+  fn waitpid(&mut self, options: libc::c_int) -> Result<Option<ExitStatus>, Error> {
+    if let Some(status) = self.status {
+      return Ok(Some(status));
+    }
+    loop {
+      let mut status = 0;
+      let res = unsafe { libc::waitpid(self.pid, &mut status, options) };
+      if res < 0 {
+        let e = Error::last_os_error();
+        if e.kind() == ErrorKind::Interrupted {
+          continue;
+        }
+        return Err(e);
+      }
+      if res == 0 {
+        return Ok(None);
+      }
+      if let Some(status) = ExitStatus::from_wait_status(status) {
+        self.status = Some(status);
+        return Ok(Some(status));
+      }
+    }
+  }
+
+  /// Waits for the child to exit, and reaps it.
+  pub fn wait(&mut self) -> Result<ExitStatus, Error> {
+    loop {
+      if let Some(status) = self.waitpid(0)? {
+        return Ok(status);
+      }
+    }
+  }
+
+  /// Reaps the child if it has exited; returns `None` if it is still
+  /// running.
+  pub fn try_wait(&mut self) -> Result<Option<ExitStatus>, Error> {
+    self.waitpid(libc::WNOHANG)
+  }
+}
+
+#[cfg(test)]
+mod tests {
+  use super::*;
+
+  use std::fs::{File};
+  use std::os::unix::io::{FromRawFd};
+
+  fn pipe(flags: libc::c_int) -> (File, File) {
+    let mut p = [-1; 2];
+    assert_eq!(unsafe { libc::pipe2(p.as_mut_ptr(), flags) }, 0);
+    unsafe { (File::from_raw_fd(p[0]), File::from_raw_fd(p[1])) }
+  }
+
+  /// Runs `script` with `/bin/sh -c`, in which `$P` names the parent's pid,
+  /// so that `[ /proc/$$/fd/N -ef /proc/$P/fd/M ]` tests whether fd `N` in
+  /// the child refers to the same file as fd `M` in the parent.
+  fn run_sh(cmd: &mut Command, script: &str) -> ExitStatus {
+    cmd.arg("-c").arg(script).env("P", std::process::id().to_string());
+    cmd.spawn().unwrap().wait().unwrap()
+  }
+
+  #[test]
+  fn swapped_fds() {
+    let (_, a) = pipe(libc::O_CLOEXEC);
+    let (_, b) = pipe(libc::O_CLOEXEC);
+    let (a_fd, b_fd) = (a.as_raw_fd(), b.as_raw_fd());
+    let status = run_sh(Command::new("/bin/sh").fd(3, &b).fd(4, &a),
+        &format!("[ /proc/$$/fd/3 -ef /proc/$P/fd/{} ] && [ /proc/$$/fd/4 -ef /proc/$P/fd/{} ]", b_fd, a_fd));
+    assert!(status.success(), "{}", status);
+    // NB: Each source fd is also the target of the other mapping.
+    let status = run_sh(Command::new("/bin/sh").fd(a_fd, &b).fd(b_fd, &a),
+        &format!("[ /proc/$$/fd/{0} -ef /proc/$P/fd/{1} ] && [ /proc/$$/fd/{1} -ef /proc/$P/fd/{0} ]", a_fd, b_fd));
+    assert!(status.success(), "{}", status);
+  }
+
+  #[test]
+  fn fds_onto_stdio() {
+    let (r, _w) = pipe(libc::O_CLOEXEC);
+    let (_r, w) = pipe(libc::O_CLOEXEC);
+    let (_, e) = pipe(libc::O_CLOEXEC);
+    let status = run_sh(Command::new("/bin/sh").fd(0, &r).fd(1, &w).fd(2, &e).fd(3, &w),
+        &format!(
+          "[ /proc/$$/fd/0 -ef /proc/$P/fd/{} ] && [ /proc/$$/fd/1 -ef /proc/$P/fd/{} ] && [ /proc/$$/fd/2 -ef /proc/$P/fd/{} ] && [ /proc/$$/fd/3 -ef /proc/$$/fd/1 ]",
+          r.as_raw_fd(), w.as_raw_fd(), e.as_raw_fd()));
+    assert!(status.success(), "{}", status);
+  }
+
+  #[test]
+  fn close_stdout() {
+    let status = run_sh(Command::new("/bin/sh").close_fd(1),
+        "[ ! -e /proc/$$/fd/1 ] && [ -e /proc/$$/fd/0 ] && [ -e /proc/$$/fd/2 ]");
+    assert!(status.success(), "{}", status);
+  }
+
+  #[test]
+  fn unmapped_fds_are_not_inherited() {
+    // NB: Not `FD_CLOEXEC`, so it would survive `execve` if left open.
+    let (r, w) = pipe(0);
+    let status = run_sh(Command::new("/bin/sh").fd(5, &w),
+        &format!("[ ! -e /proc/$$/fd/{} ] && [ ! -e /proc/$$/fd/{} ] && [ /proc/$$/fd/5 -ef /proc/$P/fd/{} ]",
+          r.as_raw_fd(), w.as_raw_fd(), w.as_raw_fd()));
+    assert!(status.success(), "{}", status);
+  }
+
+  fn spawn_error(cmd: &Command) -> (ErrorKind, SpawnStage, Option<i32>) {
+    let e = cmd.spawn().unwrap_err();
+    let kind = e.kind();
+    let err = e.into_inner().unwrap().downcast::<SpawnError>().unwrap();
+    (kind, err.stage, err.error.raw_os_error())
+  }
+
+  #[test]
+  fn chdir_failure_is_reported() {
+    let mut cmd = Command::new("/bin/sh");
+    cmd.arg("-c").arg("exit 0").current_dir("/nonexistent/unix2-spawn");
+    assert_eq!(spawn_error(&cmd), (ErrorKind::NotFound, SpawnStage::Chdir, Some(libc::ENOENT)));
+  }
+
+  #[test]
+  fn exec_failure_is_reported() {
+    let cmd = Command::new("/nonexistent/unix2-spawn");
+    assert_eq!(spawn_error(&cmd), (ErrorKind::NotFound, SpawnStage::Exec, Some(libc::ENOENT)));
+    // NB: Not executable.
+    let cmd = Command::new("/dev/null");
+    assert_eq!(spawn_error(&cmd), (ErrorKind::PermissionDenied, SpawnStage::Exec, Some(libc::EACCES)));
+  }
+}
@@ -0,0 +1,260 @@
+use std::io::{Error, ErrorKind};
+use std::mem::{zeroed};
+use std::ops::{BitOr};
+use std::os::unix::io::{AsRawFd, FromRawFd, IntoRawFd, RawFd};
+use std::time::{Duration};
+
+use crate::{to_timespec};
+
+/// The clock that a `TimerFd` measures time against.
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+pub enum Clock {
+  /// Nonsettable, monotonically increasing clock; does not advance while the
+  /// system is suspended.
+  Monotonic,
+  /// Settable system-wide wall clock.
+  Realtime,
+  /// Like `Monotonic`, but also advances while the system is suspended.
+  Boottime,
+  /// Like `Realtime`, but wakes the system if it is suspended; requires
+  /// `CAP_WAKE_ALARM`.
+  RealtimeAlarm,
+  /// Like `Boottime`, but wakes the system if it is suspended; requires
+  /// `CAP_WAKE_ALARM`.
+  BoottimeAlarm,
+}
+
+impl Clock {
+  pub fn to_raw(self) -> libc::clockid_t {
+    match self {
+      Clock::Monotonic => libc::CLOCK_MONOTONIC,
+      Clock::Realtime => libc::CLOCK_REALTIME,
+      Clock::Boottime => libc::CLOCK_BOOTTIME,
+      Clock::RealtimeAlarm => libc::CLOCK_REALTIME_ALARM,
+      Clock::BoottimeAlarm => libc::CLOCK_BOOTTIME_ALARM,
+    }
+  }
+
+  /// Returns the current time of the clock, e.g. for computing absolute
+  /// deadlines for `TFD_TIMER_ABSTIME`.
+  pub fn now(self) -> Result<Duration, Error> {
+    let mut ts: libc::timespec = unsafe { zeroed() };
+    let res = unsafe { libc::clock_gettime(self.to_raw(), &mut ts) };
+    if res != 0 {
+      return Err(Error::last_os_error());
+    }
+    Ok(from_timespec(&ts))
+  }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash, Debug)]
+#[repr(transparent)]
+pub struct TimerFdFlags {
+  pub bits: libc::c_int,
+}
+
+impl TimerFdFlags {
+  #[inline]
+  pub fn empty() -> TimerFdFlags {
+    TimerFdFlags{bits: 0}
+  }
+
+  #[inline]
+  pub fn bits(&self) -> libc::c_int {
+    self.bits
+  }
+
+  #[inline]
+  pub fn contains(&self, other: TimerFdFlags) -> bool {
+    (self.bits & other.bits) == other.bits
+  }
+}
+
+impl BitOr for TimerFdFlags {
+  type Output = TimerFdFlags;
+
+  #[inline]
+  fn bitor(self, rhs: TimerFdFlags) -> TimerFdFlags {
+    TimerFdFlags{bits: self.bits | rhs.bits}
+  }
+}
+
+/// Set `FD_CLOEXEC` on the new file descriptor (for `TimerFd::create`).
+pub const TFD_CLOEXEC: TimerFdFlags = TimerFdFlags{bits: libc::TFD_CLOEXEC};
+
+/// Set `O_NONBLOCK` on the new file descriptor (for `TimerFd::create`).
+pub const TFD_NONBLOCK: TimerFdFlags = TimerFdFlags{bits: libc::TFD_NONBLOCK};
+
+/// Interpret the initial expiration as an absolute time on the timer's
+/// clock, rather than relative to now (for `TimerFd::set`).
+pub const TFD_TIMER_ABSTIME: TimerFdFlags = TimerFdFlags{bits: libc::TFD_TIMER_ABSTIME};
+
+/// Together with `TFD_TIMER_ABSTIME` on a `Realtime` or `RealtimeAlarm`
+/// timer, make `read` fail with `ECANCELED` if the clock is set
+/// discontinuously (for `TimerFd::set`).
+pub const TFD_TIMER_CANCEL_ON_SET: TimerFdFlags = TimerFdFlags{bits: libc::TFD_TIMER_CANCEL_ON_SET};
+
+/// The expiration settings of a timer.
+#[derive(Clone, Copy, Default, PartialEq, Eq, Debug)]
+pub struct TimerSpec {
+  /// The (initial) expiration; `None` means the timer is disarmed.
+  pub value:    Option<Duration>,
+  /// The period of subsequent expirations; `None` means one-shot.
+  pub interval: Option<Duration>,
+}
+
+impl TimerSpec {
+  pub fn oneshot(after: Duration) -> TimerSpec {
+    TimerSpec{value: Some(after), interval: None}
+  }
+
+  /// A periodic timer, first expiring after one `period`.
+  pub fn interval(period: Duration) -> TimerSpec {
+    TimerSpec{value: Some(period), interval: Some(period)}
+  }
+
+  fn to_raw(self) -> libc::itimerspec {
+    let mut raw: libc::itimerspec = unsafe { zeroed() };
+    // NB: A zero `it_value` disarms the timer, so an explicit zero
+    // expiration is bumped to the smallest nonzero value.
+    raw.it_value = match self.value {
+      None => to_timespec(Duration::from_secs(0)),
+      Some(t) if t == Duration::from_secs(0) => to_timespec(Duration::from_nanos(1)),
+      Some(t) => to_timespec(t),
+    };
+    raw.it_interval = to_timespec(self.interval.unwrap_or_default());
+    raw
+  }
+
+  fn from_raw(raw: &libc::itimerspec) -> TimerSpec {
+    let nonzero = |d: Duration| if d == Duration::from_secs(0) { None } else { Some(d) };
+    TimerSpec{
+      value:    nonzero(from_timespec(&raw.it_value)),
+      interval: nonzero(from_timespec(&raw.it_interval)),
+    }
+  }
+}
+
+fn from_timespec(ts: &libc::timespec) -> Duration {
+  Duration::new(ts.tv_sec.max(0) as u64, ts.tv_nsec.max(0) as u32)
+}
+
+/// A timer that delivers expirations via a file descriptor, which becomes
+/// readable (e.g. `EPOLLIN` in an `Epoll`) when the timer expires.
+#[derive(Debug)]
+pub struct TimerFd {
+  fd: RawFd,
+}
+
+impl Drop for TimerFd {
+  fn drop(&mut self) {
+    unsafe { libc::close(self.fd); }
+  }
+}
+
+impl TimerFd {
+  /// Creates a new, disarmed timer.
+  ///
+  /// ## Notes
+  ///
+  /// * `timerfd_create()` is the underlying syscall.
+  pub fn create(clock: Clock, flags: TimerFdFlags) -> Result<TimerFd, Error> {
+    let fd = unsafe { libc::timerfd_create(clock.to_raw(), flags.bits()) };
+    if fd < 0 {
+      return Err(Error::last_os_error());
+    }
+    Ok(TimerFd{fd})
+  }
+
+  /// Arms (or, if `spec.value` is `None`, disarms) the timer, and returns
+  /// the previous setting.
+  ///
+  /// ## Notes
+  ///
+  /// * `timerfd_settime()` is the underlying syscall.
+  pub fn set(&self, spec: TimerSpec, flags: TimerFdFlags) -> Result<TimerSpec, Error> {
+    let new = spec.to_raw();
+    let mut old: libc::itimerspec = unsafe { zeroed() };
+    let res = unsafe { libc::timerfd_settime(self.fd, flags.bits(), &new, &mut old) };
+    if res != 0 {
+      return Err(Error::last_os_error());
+    }
+    Ok(TimerSpec::from_raw(&old))
+  }
+
+  /// Arms a one-shot timer expiring `after` from now.
+  pub fn set_oneshot(&self, after: Duration) -> Result<(), Error> {
+    self.set(TimerSpec::oneshot(after), TimerFdFlags::empty())?;
+    Ok(())
+  }
+
+  /// Arms a periodic timer expiring every `period`, starting one `period`
+  /// from now.
+  pub fn set_interval(&self, period: Duration) -> Result<(), Error> {
+    self.set(TimerSpec::interval(period), TimerFdFlags::empty())?;
+    Ok(())
+  }
+
+  /// Arms a one-shot timer expiring at the absolute time `deadline` on the
+  /// timer's clock (see `Clock::now`).
+  pub fn set_deadline(&self, deadline: Duration) -> Result<(), Error> {
+    self.set(TimerSpec::oneshot(deadline), TFD_TIMER_ABSTIME)?;
+    Ok(())
+  }
+
+  pub fn disarm(&self) -> Result<(), Error> {
+    self.set(TimerSpec::default(), TimerFdFlags::empty())?;
+    Ok(())
+  }
+
+  /// Returns the time until the next expiration, and the interval.
+  ///
+  /// ## Notes
+  ///
+  /// * `timerfd_gettime()` is the underlying syscall.
+  pub fn get(&self) -> Result<TimerSpec, Error> {
+    let mut cur: libc::itimerspec = unsafe { zeroed() };
+    let res = unsafe { libc::timerfd_gettime(self.fd, &mut cur) };
+    if res != 0 {
+      return Err(Error::last_os_error());
+    }
+    Ok(TimerSpec::from_raw(&cur))
+  }
+
+  /// Returns the number of expirations since the last read, blocking until
+  /// at least one if the timer is not `TFD_NONBLOCK`.
+  ///
+  /// Fails with `ECANCELED` if the timer was set with
+  /// `TFD_TIMER_CANCEL_ON_SET` and the clock was changed.
+  pub fn read(&self) -> Result<u64, Error> {
+    let mut val: u64 = 0;
+    let res = unsafe { libc::read(self.fd, &mut val as *mut u64 as *mut _, 8) };
+    if res < 0 {
+      return Err(Error::last_os_error());
+    }
+    if res != 8 {
+      return Err(Error::new(ErrorKind::UnexpectedEof, "short timerfd read"));
+    }
+    Ok(val)
+  }
+}
+
+impl AsRawFd for TimerFd {
+  fn as_raw_fd(&self) -> RawFd {
+    self.fd
+  }
+}
+
+impl IntoRawFd for TimerFd {
+  fn into_raw_fd(self) -> RawFd {
+    let fd = self.fd;
+    std::mem::forget(self);
+    fd
+  }
+}
+
+impl FromRawFd for TimerFd {
+  unsafe fn from_raw_fd(fd: RawFd) -> TimerFd {
+    TimerFd{fd}
+  }
+}
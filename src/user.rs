@@ -0,0 +1,228 @@
+use std::ffi::{CStr, CString};
+use std::io::{Error, ErrorKind};
+use std::mem::{MaybeUninit};
+use std::os::raw::{c_char};
+use std::ptr::{null_mut};
+
+/// An owned copy of a `struct passwd` entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Passwd {
+  pub name:   String,
+  pub uid:    u32,
+  pub gid:    u32,
+  pub gecos:  String,
+  pub home:   String,
+  pub shell:  String,
+}
+
+/// An owned copy of a `struct group` entry.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Group {
+  pub name:     String,
+  pub gid:      u32,
+  pub members:  Vec<String>,
+}
+
+fn init_buf_len(key: libc::c_int) -> usize {
+  let n = unsafe { libc::sysconf(key) };
+  if n <= 0 {
+    1024
+  } else {
+    n as usize
+  }
+}
+
+fn to_cstring(s: &str) -> Result<CString, Error> {
+  CString::new(s).map_err(|_| Error::new(ErrorKind::InvalidInput, "name contains a nul byte"))
+}
+
+unsafe fn from_cstr(p: *const c_char) -> String {
+  if p.is_null() {
+    return String::new();
+  }
+  CStr::from_ptr(p).to_string_lossy().into_owned()
+}
+
+/// Calls one of the reentrant `get*_r` functions, growing the scratch buffer
+/// on `ERANGE`.
+fn lookup_r<T, F>(key: libc::c_int, mut f: F) -> Result<Option<T>, Error>
+where F: FnMut(*mut c_char, usize) -> (libc::c_int, Option<T>) {
+  let mut buf: Vec<c_char> = Vec::with_capacity(init_buf_len(key));
+  loop {
+    let (res, val) = f(buf.as_mut_ptr(), buf.capacity());
+    if res == libc::ERANGE {
+      let cap = buf.capacity() * 2;
+      buf.reserve(cap);
+      continue;
+    }
+    if res != 0 {
+      return Err(Error::from_raw_os_error(res));
+    }
+    return Ok(val);
+  }
+}
+
+impl Passwd {
+  unsafe fn from_raw(pw: &libc::passwd) -> Passwd {
+    Passwd{
+      name:   from_cstr(pw.pw_name),
+      uid:    pw.pw_uid,
+      gid:    pw.pw_gid,
+      gecos:  from_cstr(pw.pw_gecos),
+      home:   from_cstr(pw.pw_dir),
+      shell:  from_cstr(pw.pw_shell),
+    }
+  }
+
+  /// Looks up a user by name using `getpwnam_r`.
+  ///
+  /// Returns `Ok(None)` if there is no such user.
+  pub fn from_name(name: &str) -> Result<Option<Passwd>, Error> {
+    let name = to_cstring(name)?;
+    lookup_r(libc::_SC_GETPW_R_SIZE_MAX, |buf, len| unsafe {
+      let mut pw = MaybeUninit::<libc::passwd>::uninit();
+      let mut out = null_mut();
+      let res = libc::getpwnam_r(name.as_ptr(), pw.as_mut_ptr(), buf, len, &mut out);
+      if res != 0 || out.is_null() {
+        return (res, None);
+      }
+      (0, Some(Passwd::from_raw(&*out)))
+    })
+  }
+
+  /// Looks up a user by UID using `getpwuid_r`.
+  ///
+  /// Returns `Ok(None)` if there is no such user.
+  pub fn from_uid(uid: u32) -> Result<Option<Passwd>, Error> {
+    lookup_r(libc::_SC_GETPW_R_SIZE_MAX, |buf, len| unsafe {
+      let mut pw = MaybeUninit::<libc::passwd>::uninit();
+      let mut out = null_mut();
+      let res = libc::getpwuid_r(uid, pw.as_mut_ptr(), buf, len, &mut out);
+      if res != 0 || out.is_null() {
+        return (res, None);
+      }
+      (0, Some(Passwd::from_raw(&*out)))
+    })
+  }
+}
+
+impl Group {
+  unsafe fn from_raw(gr: &libc::group) -> Group {
+    let mut members = Vec::new();
+    let mut mem = gr.gr_mem;
+    if !mem.is_null() {
+      while !(*mem).is_null() {
+        members.push(from_cstr(*mem));
+        mem = mem.add(1);
+      }
+    }
+    Group{
+      name:   from_cstr(gr.gr_name),
+      gid:    gr.gr_gid,
+      members,
+    }
+  }
+
+  /// Looks up a group by name using `getgrnam_r`.
+  ///
+  /// Returns `Ok(None)` if there is no such group.
+  pub fn from_name(name: &str) -> Result<Option<Group>, Error> {
+    let name = to_cstring(name)?;
+    lookup_r(libc::_SC_GETGR_R_SIZE_MAX, |buf, len| unsafe {
+      let mut gr = MaybeUninit::<libc::group>::uninit();
+      let mut out = null_mut();
+      let res = libc::getgrnam_r(name.as_ptr(), gr.as_mut_ptr(), buf, len, &mut out);
+      if res != 0 || out.is_null() {
+        return (res, None);
+      }
+      (0, Some(Group::from_raw(&*out)))
+    })
+  }
+
+  /// Looks up a group by GID using `getgrgid_r`.
+  ///
+  /// Returns `Ok(None)` if there is no such group.
+  pub fn from_gid(gid: u32) -> Result<Option<Group>, Error> {
+    lookup_r(libc::_SC_GETGR_R_SIZE_MAX, |buf, len| unsafe {
+      let mut gr = MaybeUninit::<libc::group>::uninit();
+      let mut out = null_mut();
+      let res = libc::getgrgid_r(gid, gr.as_mut_ptr(), buf, len, &mut out);
+      if res != 0 || out.is_null() {
+        return (res, None);
+      }
+      (0, Some(Group::from_raw(&*out)))
+    })
+  }
+}
+
+/// Returns the list of groups that `user` is a member of, including `gid`,
+/// using `getgrouplist`.
+pub fn get_group_list(user: &str, gid: u32) -> Result<Vec<u32>, Error> {
+  let user = to_cstring(user)?;
+  let mut groups: Vec<u32> = vec![0; 32];
+  loop {
+    let mut ngroups = groups.len() as libc::c_int;
+    let res = unsafe {
+      libc::getgrouplist(user.as_ptr(), gid as _, groups.as_mut_ptr() as *mut _, &mut ngroups)
+    };
+    if res < 0 {
+      // NB: On failure `ngroups` is set to the required length (glibc), or
+      // left unchanged (other libcs); either way, grow and retry.
+      let len = (ngroups as usize).max(groups.len() * 2);
+      groups.resize(len, 0);
+      continue;
+    }
+    groups.truncate(ngroups as usize);
+    return Ok(groups);
+  }
+}
+
+fn resolve_user(user: &str) -> Result<Passwd, Error> {
+  let pw = match user.parse::<u32>() {
+    Ok(uid) => Passwd::from_uid(uid)?,
+    Err(_) => Passwd::from_name(user)?,
+  };
+  pw.ok_or_else(|| Error::new(ErrorKind::NotFound, format!("no such user: {}", user)))
+}
+
+fn resolve_group(group: &str) -> Result<u32, Error> {
+  if let Ok(gid) = group.parse::<u32>() {
+    return Ok(gid);
+  }
+  match Group::from_name(group)? {
+    Some(gr) => Ok(gr.gid),
+    None => Err(Error::new(ErrorKind::NotFound, format!("no such group: {}", group))),
+  }
+}
+
+/// Parses a `"user"` or `"user:group"` spec, where either part may be a
+/// name or a numeric ID, and returns the resolved `(uid, gid)`.
+///
+/// If the group is omitted, the user's primary group is used. A numeric
+/// user that has no passwd entry is only accepted together with an explicit
+/// group.
+///
+/// The result is meant to be passed to `set_gid` and then `set_uid`.
+pub fn parse_user_group(spec: &str) -> Result<(u32, u32), Error> {
+  let (user, group) = match spec.find(':') {
+    Some(i) => (&spec[ .. i], Some(&spec[i + 1 .. ])),
+    None => (spec, None),
+  };
+  if user.is_empty() {
+    return Err(Error::new(ErrorKind::InvalidInput, format!("missing user in spec: {:?}", spec)));
+  }
+  match group {
+    None | Some("") => {
+      let pw = resolve_user(user)?;
+      Ok((pw.uid, pw.gid))
+    }
+    Some(group) => {
+      let uid = match user.parse::<u32>() {
+        Ok(uid) => uid,
+        Err(_) => resolve_user(user)?.uid,
+      };
+      let gid = resolve_group(group)?;
+      Ok((uid, gid))
+    }
+  }
+}